@@ -0,0 +1,214 @@
+use crate::error::SelectorResult;
+use crate::select::SelectorExpression;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+type SelectionKey = (usize, String);
+
+#[derive(Default)]
+struct Counter {
+  hits: AtomicUsize,
+  misses: AtomicUsize,
+}
+
+impl Counter {
+  fn hit(&self) {
+    self.hits.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn miss(&self) {
+    self.misses.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+impl fmt::Display for Counter {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} hits, {} misses",
+      self.hits.load(Ordering::Relaxed),
+      self.misses.load(Ordering::Relaxed)
+    )
+  }
+}
+
+/// How many node ids the cached selections may hold between them.
+const SELECTION_CAPACITY: usize = 1 << 22;
+
+struct CachedSelection {
+  nodes: Arc<Vec<usize>>,
+  last_used: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Selections {
+  entries: HashMap<SelectionKey, CachedSelection>,
+  size: usize,
+}
+
+/// Compiled selectors by source string, and full selection results by start node and selector
+/// source. Every worker resolves against the same document, so both are shared between all
+/// requests for the lifetime of the bake. Selections from arbitrary start nodes could otherwise
+/// grow without limit, so the least recently used ones are dropped once they hold more than
+/// `capacity` node ids.
+pub(crate) struct SelectionCache {
+  selectors: RwLock<HashMap<String, Arc<SelectorExpression>>>,
+  selections: RwLock<Selections>,
+  capacity: usize,
+  clock: AtomicUsize,
+  selector_counter: Counter,
+  selection_counter: Counter,
+}
+
+impl Default for SelectionCache {
+  fn default() -> SelectionCache {
+    SelectionCache::with_capacity(SELECTION_CAPACITY)
+  }
+}
+
+impl SelectionCache {
+  fn with_capacity(capacity: usize) -> SelectionCache {
+    SelectionCache {
+      selectors: RwLock::default(),
+      selections: RwLock::default(),
+      capacity,
+      clock: AtomicUsize::new(0),
+      selector_counter: Counter::default(),
+      selection_counter: Counter::default(),
+    }
+  }
+
+  pub(crate) fn selector(&self, source: &str) -> SelectorResult<Arc<SelectorExpression>> {
+    if let Some(selector) = self.selectors.read().unwrap().get(source) {
+      self.selector_counter.hit();
+      return Ok(selector.clone());
+    }
+    self.selector_counter.miss();
+    let selector = Arc::new(SelectorExpression::from_string(source)?);
+    self
+      .selectors
+      .write()
+      .unwrap()
+      .insert(source.to_owned(), selector.clone());
+    Ok(selector)
+  }
+
+  pub(crate) fn selection<F: FnOnce() -> Vec<usize>>(
+    &self,
+    node_id: usize,
+    source: &str,
+    resolve: F,
+  ) -> Arc<Vec<usize>> {
+    let key = (node_id, source.to_owned());
+    if let Some(selection) = self.lookup(&key) {
+      self.selection_counter.hit();
+      return selection;
+    }
+    self.selection_counter.miss();
+    let selection = Arc::new(resolve());
+    self.insert(key, selection.clone());
+    selection
+  }
+
+  pub(crate) fn cached_selection(&self, node_id: usize, source: &str) -> Option<Arc<Vec<usize>>> {
+    let selection = self.lookup(&(node_id, source.to_owned()));
+    match selection {
+      Some(_) => self.selection_counter.hit(),
+      None => self.selection_counter.miss(),
+    }
+    selection
+  }
+
+  fn lookup(&self, key: &SelectionKey) -> Option<Arc<Vec<usize>>> {
+    let selections = self.selections.read().unwrap();
+    let cached = selections.entries.get(key)?;
+    let now = self.clock.fetch_add(1, Ordering::Relaxed);
+    cached.last_used.store(now, Ordering::Relaxed);
+    Some(cached.nodes.clone())
+  }
+
+  /// Evicts down to three quarters of the capacity at a time, so that a full cache is not
+  /// sorted again on every insert.
+  fn insert(&self, key: SelectionKey, nodes: Arc<Vec<usize>>) {
+    if nodes.len() > self.capacity {
+      return;
+    }
+    let mut selections = self.selections.write().unwrap();
+    if selections.entries.contains_key(&key) {
+      return;
+    }
+    if selections.size + nodes.len() > self.capacity {
+      let target = (self.capacity - self.capacity / 4).saturating_sub(nodes.len());
+      let mut by_use: Vec<(usize, SelectionKey)> = selections
+        .entries
+        .iter()
+        .map(|(key, cached)| (cached.last_used.load(Ordering::Relaxed), key.clone()))
+        .collect();
+      by_use.sort_unstable_by_key(|(last_used, _)| *last_used);
+      for (_, evicted) in by_use {
+        if selections.size <= target {
+          break;
+        }
+        let cached = selections
+          .entries
+          .remove(&evicted)
+          .expect("Listed from the map.");
+        selections.size -= cached.nodes.len();
+      }
+    }
+    let now = self.clock.fetch_add(1, Ordering::Relaxed);
+    selections.size += nodes.len();
+    selections.entries.insert(
+      key,
+      CachedSelection {
+        nodes,
+        last_used: AtomicUsize::new(now),
+      },
+    );
+  }
+}
+
+impl fmt::Display for SelectionCache {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "selectors {}; selections {}",
+      self.selector_counter, self.selection_counter
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lookups_without_a_cached_selection_count_as_misses() {
+    let cache = SelectionCache::default();
+    assert!(cache.cached_selection(0, "//a").is_none());
+    cache.selection(0, "//a", || vec![1, 2]);
+    assert_eq!(*cache.cached_selection(0, "//a").unwrap(), [1, 2]);
+    assert_eq!(*cache.selection(0, "//a", || unreachable!()), [1, 2]);
+    assert_eq!(
+      cache.to_string(),
+      "selectors 0 hits, 0 misses; selections 2 hits, 2 misses"
+    );
+  }
+
+  #[test]
+  fn least_recently_used_selections_are_evicted() {
+    let cache = SelectionCache::with_capacity(8);
+    cache.selection(1, "//a", || vec![1, 2, 3]);
+    cache.selection(2, "//a", || vec![4, 5, 6]);
+    cache.cached_selection(1, "//a");
+    cache.selection(3, "//a", || vec![7, 8, 9]);
+    assert!(cache.cached_selection(1, "//a").is_some());
+    assert!(cache.cached_selection(2, "//a").is_none());
+    assert!(cache.cached_selection(3, "//a").is_some());
+    cache.selection(4, "//a", || (0..9).collect());
+    assert!(cache.cached_selection(4, "//a").is_none());
+    assert!(cache.cached_selection(1, "//a").is_some());
+  }
+}
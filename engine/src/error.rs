@@ -1,3 +1,4 @@
+use crate::schema::QualifiedName;
 use std::error;
 use std::fmt;
 use std::io;
@@ -49,13 +50,313 @@ impl fmt::Display for OvenError {
   }
 }
 
+pub type SelectorResult<T> = Result<T, SelectorError>;
+
+#[derive(Debug)]
+pub enum SelectorError {
+  /// The parser does not say where in a path it failed, so `path_offset` is where the path that
+  /// failed to parse starts, counted in characters.
+  ParseError {
+    error: scandent::ScandentError,
+    path_offset: usize,
+  },
+  MissingOperand {
+    offset: usize,
+  },
+  CheckError {
+    step: usize,
+    function: String,
+    reason: CheckErrorReason,
+  },
+}
+
+#[derive(Debug)]
+pub enum CheckErrorReason {
+  UnknownFunction,
+  ArgumentCount {
+    min: usize,
+    max: usize,
+    found: usize,
+  },
+  BadArgument {
+    index: usize,
+    expected: &'static str,
+  },
+}
+
+impl SelectorError {
+  /// Attributes an error from a nested selector to the step of the outer selector containing it.
+  pub fn in_step(self, step: usize) -> SelectorError {
+    match self {
+      SelectorError::CheckError {
+        function, reason, ..
+      } => SelectorError::CheckError {
+        step,
+        function,
+        reason,
+      },
+      err => err,
+    }
+  }
+
+  /// Attributes a parse error to the path of an expression starting at `path_offset`.
+  pub fn in_path_at(self, path_offset: usize) -> SelectorError {
+    match self {
+      SelectorError::ParseError { error, .. } => SelectorError::ParseError { error, path_offset },
+      err => err,
+    }
+  }
+}
+
+impl From<scandent::ScandentError> for SelectorError {
+  fn from(err: scandent::ScandentError) -> SelectorError {
+    SelectorError::ParseError {
+      error: err,
+      path_offset: 0,
+    }
+  }
+}
+
+impl error::Error for SelectorError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match &self {
+      SelectorError::ParseError { error, .. } => Some(error),
+      SelectorError::MissingOperand { .. } | SelectorError::CheckError { .. } => None,
+    }
+  }
+}
+
+impl fmt::Display for CheckErrorReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self {
+      CheckErrorReason::UnknownFunction => write!(f, "unknown check function"),
+      CheckErrorReason::ArgumentCount { min, max, found } if min == max => {
+        write!(f, "expected {} argument(s), found {}", min, found)
+      }
+      CheckErrorReason::ArgumentCount { min, max, found } => {
+        write!(f, "expected {} to {} arguments, found {}", min, max, found)
+      }
+      CheckErrorReason::BadArgument { index, expected } => {
+        write!(f, "argument {} should be {}", index, expected)
+      }
+    }
+  }
+}
+
+impl fmt::Display for SelectorError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self {
+      SelectorError::ParseError { error, path_offset } => write!(
+        f,
+        "ParseError: {} (in the path starting at offset {})",
+        error, path_offset
+      ),
+      SelectorError::MissingOperand { offset } => write!(
+        f,
+        "MissingOperand: expected a selector at offset {}",
+        offset
+      ),
+      SelectorError::CheckError {
+        step,
+        function,
+        reason,
+      } => write!(f, "CheckError: `{}` in step {}: {}", function, step, reason),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum SerializationError {
+  BadWrite,
+  UnexpectedEOF,
+  UnsetURI(String),
+  UnexpectedInstruction,
+  UnknownNode {
+    node_id: usize,
+    selector: String,
+  },
+  /// Transforms that end up replacing the node they started from, in the order they were
+  /// followed.
+  ReplaceCycle(Vec<ReplaceStep>),
+}
+
+/// A transform result that was being written out when a cycle was found.
+#[derive(Debug, Clone)]
+pub struct ReplaceStep {
+  pub node_id: usize,
+  pub mode: String,
+  pub selector: String,
+}
+
+impl error::Error for SerializationError {}
+
+impl fmt::Display for SerializationError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self {
+      SerializationError::BadWrite => write!(f, "BadWrite: could not write output"),
+      SerializationError::UnexpectedEOF => {
+        write!(f, "UnexpectedEOF: instructions ended inside a start tag")
+      }
+      SerializationError::UnsetURI(uri) => {
+        write!(f, "UnsetURI: no prefix is declared for namespace {:?}", uri)
+      }
+      SerializationError::UnexpectedInstruction => {
+        write!(f, "UnexpectedInstruction: instruction is not valid here")
+      }
+      SerializationError::UnknownNode { node_id, selector } => write!(
+        f,
+        "UnknownNode: transform {:?} replaces node {}, which does not exist",
+        selector, node_id
+      ),
+      SerializationError::ReplaceCycle(chain) => {
+        write!(f, "ReplaceCycle: ")?;
+        for (index, step) in chain.iter().enumerate() {
+          match index {
+            0 => {}
+            1 => write!(f, " replaces ")?,
+            _ => write!(f, ", which replaces ")?,
+          }
+          write!(
+            f,
+            "node {} in mode {:?} (transform {:?})",
+            step.node_id, step.mode, step.selector
+          )?;
+        }
+        match chain.first() {
+          Some(_) if chain.len() == 1 => write!(f, " replaces itself"),
+          Some(first) => write!(
+            f,
+            ", which replaces node {} in mode {:?} again",
+            first.node_id, first.mode
+          ),
+          None => Ok(()),
+        }
+      }
+    }
+  }
+}
+
+/// What is wrong with the instructions of a transform result.
+#[derive(Debug)]
+pub enum InstructionError {
+  UnopenedEnd(QualifiedName),
+  MismatchedEnd {
+    start: QualifiedName,
+    end: QualifiedName,
+  },
+  Unclosed(QualifiedName),
+  MisplacedAttributes,
+  MisplacedNamespaces,
+  Document,
+  UnknownNode(usize),
+}
+
+impl fmt::Display for InstructionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let expanded = |name: &QualifiedName| format!("{{{}}}{}", name.uri, name.local_name);
+    match &self {
+      InstructionError::UnopenedEnd(end) => {
+        write!(f, "end tag {} has no start tag", expanded(end))
+      }
+      InstructionError::MismatchedEnd { start, end } => write!(
+        f,
+        "end tag {} does not match start tag {}",
+        expanded(end),
+        expanded(start)
+      ),
+      InstructionError::Unclosed(start) => {
+        write!(f, "start tag {} is never closed", expanded(start))
+      }
+      InstructionError::MisplacedAttributes => write!(f, "attributes do not follow a start tag"),
+      InstructionError::MisplacedNamespaces => write!(f, "namespaces do not follow a start tag"),
+      InstructionError::Document => write!(f, "only the root may be written as a document"),
+      InstructionError::UnknownNode(node_id) => write!(f, "node {} does not exist", node_id),
+    }
+  }
+}
+
+/// A transform result that was refused when it arrived.
+#[derive(Debug)]
+pub struct InvalidResult {
+  pub node_id: usize,
+  pub mode: String,
+  pub selector: String,
+  /// Index of the offending instruction, if the problem is with one in particular.
+  pub instruction: Option<usize>,
+  pub problem: InstructionError,
+}
+
+impl error::Error for InvalidResult {}
+
+impl fmt::Display for InvalidResult {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "transform {:?} on node {} in mode {:?}: ",
+      self.selector, self.node_id, self.mode
+    )?;
+    if let Some(instruction) = self.instruction {
+      write!(f, "instruction {}: ", instruction)?;
+    }
+    self.problem.fmt(f)
+  }
+}
+
+pub type QueryResult<T> = Result<T, QueryError>;
+
+/// Why a request about the document could not be answered.
+#[derive(Debug)]
+pub enum QueryError {
+  Selector(SelectorError),
+  UnknownNode(usize),
+  Serialization(SerializationError),
+}
+
+impl From<SelectorError> for QueryError {
+  fn from(err: SelectorError) -> QueryError {
+    QueryError::Selector(err)
+  }
+}
+
+impl From<SerializationError> for QueryError {
+  fn from(err: SerializationError) -> QueryError {
+    QueryError::Serialization(err)
+  }
+}
+
+impl error::Error for QueryError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match &self {
+      QueryError::Selector(err) => Some(err),
+      QueryError::UnknownNode(_) => None,
+      QueryError::Serialization(err) => Some(err),
+    }
+  }
+}
+
+impl fmt::Display for QueryError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match &self {
+      QueryError::Selector(err) => err.fmt(f),
+      QueryError::UnknownNode(node_id) => write!(f, "UnknownNode: no node has id {}", node_id),
+      QueryError::Serialization(err) => write!(f, "SerializationError: {}", err),
+    }
+  }
+}
+
 pub type RequestResult<T> = Result<T, RequestError>;
 
 #[derive(Debug)]
 pub enum RequestError {
   ScandentParseError(scandent::ScandentError),
   ChildTerminated(String),
+  /// A transform failed on a selector the engine could not parse or evaluate.
+  TransformSelector(String),
+  IncompatibleWorker(String),
+  InvalidResult(Box<InvalidResult>),
   JsonParseError(serde_json::Error),
+  MessagePackDecodeError(rmp_serde::decode::Error),
+  MessagePackEncodeError(rmp_serde::encode::Error),
   Misc(io::Error),
 }
 
@@ -71,6 +372,18 @@ impl From<serde_json::Error> for RequestError {
   }
 }
 
+impl From<rmp_serde::decode::Error> for RequestError {
+  fn from(err: rmp_serde::decode::Error) -> RequestError {
+    RequestError::MessagePackDecodeError(err)
+  }
+}
+
+impl From<rmp_serde::encode::Error> for RequestError {
+  fn from(err: rmp_serde::encode::Error) -> RequestError {
+    RequestError::MessagePackEncodeError(err)
+  }
+}
+
 impl From<io::Error> for RequestError {
   fn from(err: io::Error) -> RequestError {
     RequestError::Misc(err)
@@ -85,15 +398,75 @@ impl fmt::Display for RequestError {
       match &self {
         RequestError::ScandentParseError(..) => "ScandentParseError",
         RequestError::ChildTerminated(..) => "ChildTerminated",
+        RequestError::TransformSelector(..) => "TransformSelector",
+        RequestError::IncompatibleWorker(..) => "IncompatibleWorker",
+        RequestError::InvalidResult(..) => "InvalidResult",
         RequestError::JsonParseError(..) => "JsonParseError",
+        RequestError::MessagePackDecodeError(..) => "MessagePackDecodeError",
+        RequestError::MessagePackEncodeError(..) => "MessagePackEncodeError",
         RequestError::Misc(..) => "Misc",
       },
       match &self {
         RequestError::ScandentParseError(err) => err.to_string(),
         RequestError::ChildTerminated(err) => err.clone(),
+        RequestError::TransformSelector(err) => err.clone(),
+        RequestError::IncompatibleWorker(err) => err.clone(),
+        RequestError::InvalidResult(err) => err.to_string(),
         RequestError::JsonParseError(err) => err.to_string(),
+        RequestError::MessagePackDecodeError(err) => err.to_string(),
+        RequestError::MessagePackEncodeError(err) => err.to_string(),
         RequestError::Misc(err) => err.to_string(),
       }
     )
   }
 }
+
+impl RequestError {
+  pub fn exit_code(&self) -> ExitCode {
+    match &self {
+      RequestError::ScandentParseError(_) | RequestError::TransformSelector(_) => {
+        ExitCode::Selector
+      }
+      RequestError::ChildTerminated(_) => ExitCode::Worker,
+      RequestError::IncompatibleWorker(_) => ExitCode::Protocol,
+      RequestError::InvalidResult(_) => ExitCode::InvalidResult,
+      RequestError::JsonParseError(_)
+      | RequestError::MessagePackDecodeError(_)
+      | RequestError::MessagePackEncodeError(_)
+      | RequestError::Misc(_) => ExitCode::Engine,
+    }
+  }
+}
+
+/// What the engine exits with when a bake fails, one code per kind of failure so that scripts can
+/// tell them apart. Panics exit with 101. Listed in the README too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+  /// The command line arguments are invalid. Clap exits with this code too, for the arguments it
+  /// checks itself.
+  Arguments = 2,
+  /// The document to bake could not be read or parsed.
+  Document = 3,
+  /// The engine could not talk to a worker.
+  Engine = 4,
+  /// A transform threw, or a worker failed some other way.
+  Worker = 5,
+  /// A transform produced instructions that can't be written out.
+  InvalidResult = 6,
+  /// Transforms raced for a node and the race policy is `fail`.
+  Races = 7,
+  /// The results could not be put together into a document.
+  Serialization = 8,
+  /// The output file could not be written.
+  Output = 9,
+  /// A transform used a selector that could not be parsed or evaluated.
+  Selector = 10,
+  /// A worker speaks a different protocol version than the engine.
+  Protocol = 11,
+  /// The manifest could not be found.
+  Manifest = 12,
+  /// The socket workers connect to could not be opened.
+  Socket = 13,
+  /// The node process running the workers could not be started.
+  Node = 14,
+}
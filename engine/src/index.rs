@@ -0,0 +1,169 @@
+use crate::schema::Location;
+use roxmltree::{Document, Node};
+use std::collections::HashMap;
+
+/// Lookup tables built once when the document is parsed, so that descendant steps naming an
+/// element, an id or a class do not have to walk the whole subtree.
+pub(crate) struct DocumentIndex {
+  names: HashMap<String, Vec<usize>>,
+  ids: HashMap<String, Vec<usize>>,
+  classes: HashMap<String, Vec<usize>>,
+  subtree_ends: Vec<usize>,
+}
+
+impl DocumentIndex {
+  pub(crate) fn new(document: &Document) -> DocumentIndex {
+    let nodes: Vec<Node> = document.root().descendants().collect();
+    let mut names: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut ids: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut classes: HashMap<String, Vec<usize>> = HashMap::new();
+    for node in nodes.iter().filter(|node| node.is_element()) {
+      let id = node.id().get_usize();
+      names
+        .entry(node.tag_name().name().to_owned())
+        .or_default()
+        .push(id);
+      // Attributes are indexed whatever their namespace, like the selector requirements that
+      // name none. Requirements that do name one are not answered from the index.
+      for attribute in node.attributes() {
+        match attribute.name() {
+          "id" => push_once(ids.entry(attribute.value().to_owned()).or_default(), id),
+          "class" => {
+            for token in attribute.value().split_whitespace() {
+              push_once(classes.entry(token.to_owned()).or_default(), id);
+            }
+          }
+          _ => {}
+        }
+      }
+    }
+
+    // Node ids follow document order, so a node's subtree is the range of ids from the node to
+    // its last descendant. Children come after their parents, hence the reverse walk.
+    let mut subtree_ends = vec![0; nodes.len()];
+    for node in nodes.iter().rev() {
+      let id = node.id().get_usize();
+      subtree_ends[id] = node
+        .last_child()
+        .map(|child| subtree_ends[child.id().get_usize()])
+        .unwrap_or(id);
+    }
+
+    DocumentIndex {
+      names,
+      ids,
+      classes,
+      subtree_ends,
+    }
+  }
+
+  pub(crate) fn contains(&self, id: usize) -> bool {
+    id < self.subtree_ends.len()
+  }
+
+  /// The id of the last node in the subtree rooted at `node`.
+  pub(crate) fn subtree_end(&self, node: Node) -> usize {
+    self.subtree_ends[node.id().get_usize()]
+  }
+
+  pub(crate) fn named(&self, local_name: &str) -> &[usize] {
+    self.names.get(local_name).map(Vec::as_slice).unwrap_or(&[])
+  }
+
+  /// Ids are meant to be unique, but documents do repeat them, so every element is kept.
+  pub(crate) fn with_id(&self, id: &str) -> &[usize] {
+    self.ids.get(id).map(Vec::as_slice).unwrap_or(&[])
+  }
+
+  pub(crate) fn with_class(&self, class: &str) -> &[usize] {
+    self.classes.get(class).map(Vec::as_slice).unwrap_or(&[])
+  }
+}
+
+/// Elements are visited in document order, so an element repeating a value, or carrying it in
+/// attributes from several namespaces, is only ever the last entry.
+fn push_once(entries: &mut Vec<usize>, id: usize) {
+  if entries.last() != Some(&id) {
+    entries.push(id);
+  }
+}
+
+/// Byte offsets of the start of every line in the source, for turning node positions into line
+/// and column numbers without rescanning the text each time.
+pub(crate) struct LineIndex {
+  starts: Vec<usize>,
+}
+
+impl LineIndex {
+  pub(crate) fn new(source: &str) -> LineIndex {
+    let mut starts = vec![0];
+    starts.extend(source.match_indices('\n').map(|(offset, _)| offset + 1));
+    LineIndex { starts }
+  }
+
+  /// Both numbers start at 1. Columns count characters, not bytes.
+  pub(crate) fn location(&self, source: &str, position: usize) -> Location {
+    let line = match self.starts.binary_search(&position) {
+      Ok(line) => line,
+      Err(next) => next - 1,
+    };
+    Location {
+      line: line + 1,
+      column: source[self.starts[line]..position].chars().count() + 1,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn names(document: &Document, ids: &[usize]) -> Vec<String> {
+    ids
+      .iter()
+      .map(|&id| {
+        let node = document.get_node(id.into()).unwrap();
+        node.tag_name().name().to_owned()
+      })
+      .collect()
+  }
+
+  #[test]
+  fn repeated_ids_keep_every_element() {
+    let document = Document::parse(
+      r#"<r xmlns:x="urn:x"><a id="p"/><b id="p"/><c id="p" x:id="p"/><d id="q"/></r>"#,
+    )
+    .unwrap();
+    let index = DocumentIndex::new(&document);
+    assert_eq!(names(&document, index.with_id("p")), ["a", "b", "c"]);
+    assert_eq!(names(&document, index.with_id("q")), ["d"]);
+    assert!(index.with_id("r").is_empty());
+  }
+
+  #[test]
+  fn classes_are_indexed_in_every_namespace() {
+    let document = Document::parse(
+      r#"<r xmlns:x="urn:x"><a class="p p q"/><b x:class="p"/><c class="q" x:class="q"/></r>"#,
+    )
+    .unwrap();
+    let index = DocumentIndex::new(&document);
+    assert_eq!(names(&document, index.with_class("p")), ["a", "b"]);
+    assert_eq!(names(&document, index.with_class("q")), ["a", "c"]);
+  }
+
+  #[test]
+  fn subtrees_end_at_their_last_descendant() {
+    let document = Document::parse("<r><a><b/></a><c/></r>").unwrap();
+    let index = DocumentIndex::new(&document);
+    let end = |name| {
+      let node = document
+        .descendants()
+        .find(|node| node.has_tag_name(name))
+        .unwrap();
+      names(&document, &[index.subtree_end(node)])
+    };
+    assert_eq!(end("a"), ["b"]);
+    assert_eq!(end("c"), ["c"]);
+    assert_eq!(end("r"), ["c"]);
+  }
+}
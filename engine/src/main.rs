@@ -1,10 +1,16 @@
+mod cache;
 mod error;
+mod index;
+mod protocol;
+mod races;
 mod schema;
 mod select;
+mod validate;
 
 // networking and io imports
 use std::fs;
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::Shutdown;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
 
@@ -15,11 +21,13 @@ use roxmltree::{Document, Node, NodeType};
 use std::ops::Deref;
 
 // general
-use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
-use std::process::{Command, Stdio};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::process::{self, Command, Stdio};
 
 // parallelism/concurrency
-use rayon::scope;
+use rayon::{Scope, ThreadPoolBuilder};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
@@ -29,11 +37,20 @@ use console::{style, Style};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 
 // self
-use self::error::{OvenResult, RequestError, RequestResult};
+use self::cache::SelectionCache;
+use self::error::{
+  CheckErrorReason, ExitCode, OvenResult, QueryError, QueryResult, ReplaceStep, RequestError,
+  RequestResult, SelectorError, SelectorResult, SerializationError,
+};
+use self::index::{DocumentIndex, LineIndex};
+use self::protocol::{handshake, hello, read_frame, write_frame, Incoming, MAX_PAYLOAD_LENGTH};
+use self::races::{resolve_races, Candidates, Race};
 use self::schema::{
-  Attribute, Element, Namespace, QualifiedName, Request, Response, WriteInstruction,
+  Attribute, DescriptorField, Element, Encoding, ErrorDetails, ErrorKind, Location, Namespace,
+  NodeDescriptor, NodeKind, QualifiedName, RacePolicy, Request, Response, WriteInstruction,
 };
-use self::select::{resolve_selector, ActionableSelector};
+use self::select::{own_text, resolve_expression, SelectorExpression};
+use self::validate::validate_result;
 
 rental! {
   pub mod rent_document {
@@ -51,6 +68,9 @@ use rent_document::ContainedDocument;
 
 pub struct DocumentWrapper {
   document: ContainedDocument,
+  index: DocumentIndex,
+  lines: LineIndex,
+  cache: SelectionCache,
 }
 
 impl Deref for DocumentWrapper {
@@ -63,40 +83,144 @@ impl Deref for DocumentWrapper {
 
 impl DocumentWrapper {
   fn new(source: String) -> OvenResult<DocumentWrapper> {
+    let lines = LineIndex::new(&source);
     let contained = ContainedDocument::try_new(source, |src| Document::parse(src));
     match contained {
       Ok(contained) => Ok(DocumentWrapper {
+        index: contained.rent(DocumentIndex::new),
+        lines,
+        cache: SelectionCache::default(),
         document: contained,
       }),
       Err(err) => Err(err.0.into()),
     }
   }
 
-  fn select(&self, id: usize, selector: &ActionableSelector) -> Vec<usize> {
+  /// Node ids come from workers, so every request checks them before looking the node up.
+  fn check_node(&self, id: usize) -> QueryResult<()> {
+    if self.index.contains(id) {
+      Ok(())
+    } else {
+      Err(QueryError::UnknownNode(id))
+    }
+  }
+
+  fn compile(&self, selector: &str) -> SelectorResult<Arc<SelectorExpression>> {
+    self.cache.selector(selector)
+  }
+
+  fn resolve(&self, id: usize, selector: &SelectorExpression, limit: Option<usize>) -> Vec<usize> {
     self.rent(|document| {
-      resolve_selector(document.get_node(id.into()).unwrap(), selector)
+      resolve_expression(
+        document.get_node(id.into()).expect("Already checked node."),
+        selector,
+        Some(&self.index),
+      )
+      .map(|node| node.id().get_usize())
+      .take(limit.unwrap_or(usize::MAX))
+      .collect()
+    })
+  }
+
+  /// Full selections are memoized by start node and selector source. A limited selection is
+  /// served from a cached full selection when there is one, but is never cached itself.
+  fn select(
+    &self,
+    id: usize,
+    source: &str,
+    selector: &SelectorExpression,
+    limit: Option<usize>,
+  ) -> QueryResult<Arc<Vec<usize>>> {
+    self.check_node(id)?;
+    Ok(match limit {
+      None => self
+        .cache
+        .selection(id, source, || self.resolve(id, selector, None)),
+      Some(limit) => match self.cache.cached_selection(id, source) {
+        Some(selected) if selected.len() <= limit => selected,
+        Some(selected) => Arc::new(selected[..limit].to_vec()),
+        None => Arc::new(self.resolve(id, selector, Some(limit))),
+      },
+    })
+  }
+
+  fn matches(&self, ids: &[usize], selector: &SelectorExpression) -> QueryResult<Vec<bool>> {
+    for &id in ids {
+      self.check_node(id)?;
+    }
+    Ok(self.rent(|document| {
+      ids
         .iter()
-        .filter_map(|node| match node.node_type() {
-          NodeType::Element | NodeType::Text | NodeType::Root => Some(node.id().get_usize()),
-          _ => None,
+        .map(|&id| {
+          let node = document.get_node(id.into()).expect("Already checked node.");
+          selector.matches(document.root(), node)
         })
         .collect()
-    })
+    }))
+  }
+
+  /// Node ids follow document order, so comparing them is enough.
+  fn order(&self, first: usize, second: usize) -> QueryResult<Ordering> {
+    self.check_node(first)?;
+    self.check_node(second)?;
+    Ok(first.cmp(&second))
   }
 
+  fn is_ancestor(&self, ancestor: usize, id: usize) -> QueryResult<bool> {
+    self.check_node(ancestor)?;
+    self.check_node(id)?;
+    Ok(self.rent(|document| {
+      let node = document
+        .get_node(ancestor.into())
+        .expect("Already checked node.");
+      ancestor < id && id <= self.index.subtree_end(node)
+    }))
+  }
+
+  fn closest(&self, id: usize, selector: &SelectorExpression) -> QueryResult<Option<usize>> {
+    self.check_node(id)?;
+    Ok(self.rent(|document| {
+      let node = document.get_node(id.into()).expect("Already checked node.");
+      node
+        .ancestors()
+        .skip(1)
+        .filter(Node::is_element)
+        .find(|ancestor| selector.matches(document.root(), *ancestor))
+        .map(|ancestor| ancestor.id().get_usize())
+    }))
+  }
+
+  fn node_qualified_name(node: Node) -> QualifiedName {
+    let tag = node.tag_name();
+    QualifiedName {
+      uri: tag.namespace().unwrap_or("").to_owned(),
+      local_name: match node.node_type() {
+        NodeType::Text => "#text".to_owned(),
+        NodeType::Comment => "#comment".to_owned(),
+        NodeType::PI => format!("?{}", node.pi().expect("Already checked node.").target),
+        _ => tag.name().to_owned(),
+      },
+    }
+  }
+
+  /// Only for ids the engine handed out itself, such as those of selected nodes.
   fn qualified_name(&self, id: usize) -> QualifiedName {
     self.rent(|document| {
-      let node = document.get_node(id.into()).unwrap();
-      let tag = node.tag_name();
-      QualifiedName {
-        uri: tag.namespace().unwrap_or("").to_owned(),
-        local_name: if let NodeType::Text = node.node_type() {
-          "#text"
-        } else {
-          tag.name()
-        }
-        .to_owned(),
-      }
+      Self::node_qualified_name(
+        document
+          .get_node(id.into())
+          .expect("Node from this document."),
+      )
+    })
+  }
+
+  /// Where the node starts in the source, or `None` if there is no such node.
+  fn location(&self, id: usize) -> Option<Location> {
+    self.rent_all(|contained| {
+      contained
+        .document
+        .get_node(id.into())
+        .map(|node| self.lines.location(contained.source, node.range().start))
     })
   }
 
@@ -113,36 +237,170 @@ impl DocumentWrapper {
       .collect()
   }
 
-  fn text(&self, id: usize) -> String {
-    self.rent(|document| {
-      let node = document.get_node(id.into()).unwrap();
-      Self::deep_text(node)
-    })
+  fn node_text(node: Node) -> String {
+    match node.node_type() {
+      NodeType::Comment => node.text().unwrap_or("").to_owned(),
+      NodeType::PI => node
+        .pi()
+        .expect("Already checked node.")
+        .value
+        .unwrap_or("")
+        .to_owned(),
+      _ => Self::deep_text(node),
+    }
   }
 
-  fn attributes(&self, id: usize) -> Vec<Attribute> {
-    self.rent(|document| {
-      let node = document.get_node(id.into()).unwrap();
-      node
-        .attributes()
-        .iter()
-        .map(|attribute| Attribute {
-          qualified_name: QualifiedName {
-            uri: attribute.namespace().unwrap_or("").to_owned(),
-            local_name: attribute.name().to_owned(),
-          },
-          value: attribute.value().to_owned(),
-        })
-        .collect()
-    })
+  fn text(&self, id: usize) -> QueryResult<String> {
+    self.check_node(id)?;
+    Ok(self.rent(|document| {
+      Self::node_text(document.get_node(id.into()).expect("Already checked node."))
+    }))
   }
 
-  fn to_write_instruction_queue(&self, results: &ReplacementMapping) -> Vec<WriteInstruction> {
+  fn node_attributes(node: Node) -> Vec<Attribute> {
+    node.attributes().iter().map(|a| a.into()).collect()
+  }
+
+  fn attributes(&self, id: usize) -> QueryResult<Vec<Attribute>> {
+    self.check_node(id)?;
+    Ok(self.rent(|document| {
+      Self::node_attributes(document.get_node(id.into()).expect("Already checked node."))
+    }))
+  }
+
+  fn describe(&self, id: usize, fields: &[DescriptorField]) -> QueryResult<NodeDescriptor> {
+    self.check_node(id)?;
+    Ok(self.rent(|document| {
+      let node = document.get_node(id.into()).expect("Already checked node.");
+      let mut descriptor = NodeDescriptor {
+        node_id: id,
+        ..NodeDescriptor::default()
+      };
+      for field in fields {
+        match field {
+          DescriptorField::QualifiedName => {
+            descriptor.qualified_name = Some(Self::node_qualified_name(node))
+          }
+          DescriptorField::Kind => {
+            descriptor.kind = Some(match node.node_type() {
+              NodeType::Root => NodeKind::Root,
+              NodeType::Element => NodeKind::Element,
+              NodeType::Text => NodeKind::Text,
+              NodeType::Comment => NodeKind::Comment,
+              NodeType::PI => NodeKind::ProcessingInstruction,
+            })
+          }
+          DescriptorField::Attributes => descriptor.attributes = Some(Self::node_attributes(node)),
+          DescriptorField::Namespaces => {
+            descriptor.namespaces = Some(node.namespaces().iter().map(|n| n.into()).collect())
+          }
+          DescriptorField::Parent => {
+            descriptor.parent = Some(node.parent().map(|parent| parent.id().get_usize()))
+          }
+          DescriptorField::Children => {
+            descriptor.children = Some(node.children().map(|c| c.id().get_usize()).collect())
+          }
+          DescriptorField::OwnText => descriptor.own_text = Some(own_text(node)),
+          DescriptorField::Text => descriptor.text = Some(Self::node_text(node)),
+          DescriptorField::Depth => descriptor.depth = Some(node.ancestors().count() - 1),
+          DescriptorField::SiblingIndex => {
+            descriptor.sibling_index = Some(
+              node
+                .prev_siblings()
+                .skip(1)
+                .filter(Node::is_element)
+                .count(),
+            )
+          }
+        }
+      }
+      descriptor
+    }))
+  }
+
+  fn to_write_instruction_queue(
+    &self,
+    results: &ReplacementMapping,
+  ) -> Result<Vec<WriteInstruction>, SerializationError> {
     let mut queue = vec![];
     self.rent(|document| {
-      queue_self_or_map(document, &mut queue, document.root(), "default", &results);
-    });
-    queue
+      queue_self_or_map(
+        document,
+        &mut queue,
+        document.root(),
+        "default",
+        &results,
+        &mut vec![],
+      )
+    })?;
+    Ok(queue)
+  }
+
+  /// A short rendering of a transform's output, for the race report. Nodes it replaces are shown
+  /// as comments rather than written out.
+  fn preview(&self, id: usize, instructions: &[WriteInstruction]) -> String {
+    const PREVIEW_LENGTH: usize = 120;
+    let mut queue: Vec<WriteInstruction> = instructions
+      .iter()
+      .map(|instruction| match instruction {
+        WriteInstruction::Replace { node_id, mode } => WriteInstruction::Comment {
+          text: format!("node {} in mode {:?}", node_id, mode),
+        },
+        _ => instruction.clone(),
+      })
+      .collect();
+    // Declares the prefixes the output would most likely get, those in scope where it goes.
+    if let Some(start) = queue
+      .iter()
+      .position(|instruction| matches!(instruction, WriteInstruction::StartElement { .. }))
+    {
+      let namespaces = self.rent(|document| {
+        document
+          .get_node(id.into())
+          .map(|node| node.namespaces().iter().map(|n| n.into()).collect())
+          .unwrap_or_default()
+      });
+      queue.insert(start + 1, WriteInstruction::Namespaces { namespaces });
+    }
+    let processor = XmlRsProcessor {
+      pad_self_closing: false,
+      perform_indent: false,
+    };
+    let mut buffer = vec![];
+    if let Err(err) = processor.write_queue(&mut buffer, &queue) {
+      return format!("(no preview: {})", err);
+    }
+    let rendered = String::from_utf8_lossy(&buffer);
+    if rendered.is_empty() {
+      "(nothing)".to_owned()
+    } else if rendered.chars().count() > PREVIEW_LENGTH {
+      format!(
+        "{}...",
+        rendered.chars().take(PREVIEW_LENGTH).collect::<String>()
+      )
+    } else {
+      rendered.into_owned()
+    }
+  }
+
+  fn serialize(&self, id: usize, inner: bool, processor: &XmlRsProcessor) -> QueryResult<String> {
+    self.check_node(id)?;
+    let queues: Vec<Vec<WriteInstruction>> = self.rent(|document| {
+      let node = document.get_node(id.into()).expect("Already checked node.");
+      if inner {
+        node
+          .children()
+          .map(|child| queue_standalone(document, child))
+          .collect()
+      } else {
+        queue_standalone(document, node).map(|queue| vec![queue])
+      }
+    })?;
+    let mut buffer = vec![];
+    for queue in queues {
+      processor.write_queue(&mut buffer, &queue)?;
+    }
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
   }
 }
 
@@ -247,59 +505,93 @@ impl From<&roxmltree::Namespace<'_>> for Namespace {
 }
 
 type ReplacementMapping = HashMap<(usize, String), (String, Vec<WriteInstruction>)>;
+/// `active` holds the transform results being written out around this node. Coming back to one of
+/// them would recurse forever, so it fails with the chain of transforms instead.
 fn queue_self_or_map<'a, 'b: 'a>(
   doc: &Document,
   queue: &mut Vec<WriteInstruction>,
   node: Node<'a, 'b>,
   mode: &str,
   mapping: &ReplacementMapping,
-) {
-  if let Some((_selector, instructions)) = mapping.get(&(node.id().get_usize(), mode.to_owned())) {
-    instructions.iter().for_each(|instruction| {
+  active: &mut Vec<ReplaceStep>,
+) -> Result<(), SerializationError> {
+  let node_id = node.id().get_usize();
+  if let Some((selector, instructions)) = mapping.get(&(node_id, mode.to_owned())) {
+    if let Some(start) = active
+      .iter()
+      .position(|step| step.node_id == node_id && step.mode == mode)
+    {
+      return Err(SerializationError::ReplaceCycle(active[start..].to_vec()));
+    }
+    active.push(ReplaceStep {
+      node_id,
+      mode: mode.to_owned(),
+      selector: selector.clone(),
+    });
+    for instruction in instructions {
       match instruction {
         WriteInstruction::Replace {
           node_id: replace_node_id,
           mode: replace_mode,
-        } => queue_self_or_map(
-          doc,
-          queue,
-          doc.get_node((*replace_node_id).into()).unwrap(),
-          replace_mode,
-          mapping,
-        ),
+        } => {
+          let replacement = doc.get_node((*replace_node_id).into()).ok_or_else(|| {
+            SerializationError::UnknownNode {
+              node_id: *replace_node_id,
+              selector: selector.clone(),
+            }
+          })?;
+          queue_self_or_map(doc, queue, replacement, replace_mode, mapping, active)?
+        }
         _ => queue.push(instruction.clone()),
       };
-    });
-    return;
+    }
+    active.pop();
+    return Ok(());
   }
   match node.node_type() {
     NodeType::Root => {
       queue.push(WriteInstructionKind::Document(node).into());
-      node.children().for_each(|child| {
-        queue_self_or_map(doc, queue, child, mode, mapping);
-      });
+      for child in node.children() {
+        queue_self_or_map(doc, queue, child, mode, mapping, active)?;
+      }
     }
     NodeType::Element => {
       queue.push(WriteInstructionKind::StartElement(node).into());
       queue.push(WriteInstructionKind::Namespaces(node).into());
       queue.push(WriteInstructionKind::Attributes(node).into());
-      node.children().for_each(|child| {
-        queue_self_or_map(doc, queue, child, mode, mapping);
-      });
+      for child in node.children() {
+        queue_self_or_map(doc, queue, child, mode, mapping, active)?;
+      }
       queue.push(WriteInstructionKind::EndElement(node).into());
     }
     NodeType::PI => queue.push(WriteInstructionKind::PI(node).into()),
     NodeType::Comment => queue.push(WriteInstructionKind::Comment(node).into()),
     NodeType::Text => queue.push(WriteInstructionKind::Text(node).into()),
   }
+  Ok(())
 }
 
-#[derive(Debug)]
-enum SerializationError {
-  BadWrite,
-  UnexpectedEOF,
-  UnsetURI(String),
-  UnexpectedInstruction,
+/// Queues a node as it appears in the source, declaring every namespace in scope on it so the
+/// result stands on its own.
+fn queue_standalone<'a, 'b: 'a>(
+  doc: &Document,
+  node: Node<'a, 'b>,
+) -> Result<Vec<WriteInstruction>, SerializationError> {
+  let mut queue = vec![];
+  queue_self_or_map(
+    doc,
+    &mut queue,
+    node,
+    "default",
+    &HashMap::new(),
+    &mut vec![],
+  )?;
+  // Without its ancestors around it, the start tag has to declare everything in scope rather
+  // than only what differs from the parent.
+  if let Some(WriteInstruction::Namespaces { namespaces }) = queue.get_mut(1) {
+    *namespaces = node.namespaces().iter().map(|n| n.into()).collect();
+  }
+  Ok(queue)
 }
 
 trait WriteInstructionProcessor {
@@ -449,100 +741,359 @@ impl XmlRsProcessor {
   }
 }
 
-fn handle_request(
-  document: &DocumentWrapper,
-  mut stream: UnixStream,
+impl From<QueryError> for Response {
+  fn from(err: QueryError) -> Response {
+    let mut details = ErrorDetails::default();
+    let kind = match &err {
+      QueryError::Selector(SelectorError::ParseError { path_offset, .. }) => {
+        details.path_offset = Some(*path_offset);
+        ErrorKind::SelectorParse
+      }
+      QueryError::Selector(SelectorError::MissingOperand { offset }) => {
+        details.offset = Some(*offset);
+        ErrorKind::SelectorParse
+      }
+      QueryError::Selector(SelectorError::CheckError {
+        step,
+        function,
+        reason,
+      }) => {
+        details.step = Some(*step);
+        details.function = Some(function.clone());
+        match reason {
+          CheckErrorReason::UnknownFunction => ErrorKind::UnsupportedCheck,
+          CheckErrorReason::ArgumentCount { .. } | CheckErrorReason::BadArgument { .. } => {
+            ErrorKind::BadCheckArgument
+          }
+        }
+      }
+      QueryError::UnknownNode(node_id) => {
+        details.node_id = Some(*node_id);
+        ErrorKind::UnknownNode
+      }
+      QueryError::Serialization(err) => {
+        if let SerializationError::UnknownNode { node_id, .. } = err {
+          details.node_id = Some(*node_id);
+        }
+        ErrorKind::Serialization
+      }
+    };
+    Response::BadRequest {
+      kind,
+      reason: format!("{}", err),
+      details,
+    }
+  }
+}
+
+fn record_error(state_manager: &Mutex<StateManager>, err: RequestError) {
+  let mut locked_manager = state_manager.lock().unwrap();
+  if locked_manager.error.is_none() {
+    locked_manager.error = Some(err);
+  }
+}
+
+/// Reads frames from a worker's connection until it closes. Each request is handled as its own
+/// task so that slow selections don't hold up the rest of the connection; responses are written
+/// back as they complete, tagged with the id of their request.
+fn handle_connection<'s>(
+  s: &Scope<'s>,
+  document: &'s DocumentWrapper,
+  processor: &'s XmlRsProcessor,
+  stream: UnixStream,
+  preferred: Encoding,
   state_manager: Arc<Mutex<StateManager>>,
 ) -> RequestResult<()> {
-  let mut request_string = String::new();
-  stream.read_to_string(&mut request_string)?;
-  let request = match serde_json::from_str(&request_string) {
-    Ok(request) => request,
-    Err(err) => {
-      eprintln!("{}", request_string);
-      return Err(err.into());
-    }
+  let writer = Arc::new(Mutex::new(stream.try_clone()?));
+  let mut reader = BufReader::new(stream);
+  let encoding = match handshake(&mut reader, &mut *writer.lock().unwrap(), preferred)? {
+    Some(encoding) => encoding,
+    None => return Ok(()),
   };
+  while let Some((id, incoming)) = read_frame(&mut reader, encoding)? {
+    let writer = writer.clone();
+    let state_manager = state_manager.clone();
+    s.spawn(move |_| {
+      let response = match incoming {
+        Incoming::Request(request) => handle_request(document, processor, request, &state_manager),
+        Incoming::Malformed(err) => Response::BadRequest {
+          kind: ErrorKind::MalformedRequest,
+          reason: format!("{}", err),
+          details: ErrorDetails::default(),
+        },
+        Incoming::TooLarge(length) => Response::BadRequest {
+          kind: ErrorKind::PayloadTooLarge,
+          reason: format!(
+            "PayloadTooLarge: request of {} bytes exceeds the limit of {} bytes",
+            length, MAX_PAYLOAD_LENGTH
+          ),
+          details: ErrorDetails {
+            limit: Some(MAX_PAYLOAD_LENGTH),
+            ..ErrorDetails::default()
+          },
+        },
+      };
+      let written = write_frame(&mut *writer.lock().unwrap(), id, &response, encoding);
+      if let Err(err) = written {
+        record_error(&state_manager, err);
+      }
+    });
+  }
+  Ok(())
+}
 
-  let response: Response = match request {
-    Request::Selection { node_id, selector } => match ActionableSelector::from_string(selector) {
-      Ok(sel) => {
-        let selected = document.select(node_id, &sel);
-        Response::Selection {
-          elements: selected
-            .iter()
-            .map(|&node_id| Element {
+/// Answers a request that only reads the document. Anything else is refused, which is how
+/// batches keep out requests that change state.
+fn query(document: &DocumentWrapper, processor: &XmlRsProcessor, request: Request) -> Response {
+  answer_query(document, processor, request).unwrap_or_else(|err| err.into())
+}
+
+fn answer_query(
+  document: &DocumentWrapper,
+  processor: &XmlRsProcessor,
+  request: Request,
+) -> QueryResult<Response> {
+  Ok(match request {
+    Request::Selection {
+      node_id,
+      selector,
+      limit,
+      fields,
+      locations,
+    } => {
+      let sel = document.compile(&selector)?;
+      let selected = document.select(node_id, &selector, &sel, limit)?;
+      Response::Selection {
+        elements: selected
+          .iter()
+          .map(|&node_id| {
+            Ok(Element {
               node_id,
               qualified_name: document.qualified_name(node_id),
+              descriptor: match fields {
+                Some(ref fields) => Some(document.describe(node_id, fields)?),
+                None => None,
+              },
+              location: if locations {
+                document.location(node_id)
+              } else {
+                None
+              },
             })
-            .collect(),
-        }
+          })
+          .collect::<QueryResult<_>>()?,
       }
-      Err(err) => Response::BadRequest {
-        reason: format!("{}", err),
-      },
+    }
+    Request::Matches { node_ids, selector } => Response::Matches {
+      matches: document.matches(&node_ids, &*document.compile(&selector)?)?,
     },
     Request::Text { node_id } => Response::Text {
-      text: document.text(node_id),
+      text: document.text(node_id)?,
     },
     Request::Attributes { node_id } => Response::Attributes {
-      attributes: document.attributes(node_id),
+      attributes: document.attributes(node_id)?,
+    },
+    Request::Location { node_id } => {
+      document.check_node(node_id)?;
+      Response::Location {
+        location: document.location(node_id).expect("Already checked node."),
+      }
+    }
+    Request::Describe { node_id, fields } => Response::Describe {
+      descriptor: document.describe(node_id, fields.as_deref().unwrap_or(&DescriptorField::ALL))?,
+    },
+    Request::Serialize { node_id, inner } => Response::Serialize {
+      xml: document.serialize(node_id, inner, processor)?,
+    },
+    Request::Order { first, second } => Response::Order {
+      order: document.order(first, second)? as i8,
+    },
+    Request::Ancestor { ancestor, node_id } => Response::Ancestor {
+      is_ancestor: document.is_ancestor(ancestor, node_id)?,
+    },
+    Request::Closest { node_id, selector } => Response::Closest {
+      element: document
+        .closest(node_id, &*document.compile(&selector)?)?
+        .map(|node_id| Element {
+          node_id,
+          qualified_name: document.qualified_name(node_id),
+          location: None,
+          descriptor: None,
+        }),
+    },
+    Request::Hello { .. }
+    | Request::Batch { .. }
+    | Request::PutResults { .. }
+    | Request::PutCount { .. }
+    | Request::PutComplete
+    | Request::PutError { .. }
+    | Request::HeartBeat
+    | Request::Configure { .. } => Response::BadRequest {
+      kind: ErrorKind::NotBatchable,
+      reason: "Only requests that query the document can be batched".to_string(),
+      details: ErrorDetails::default(),
+    },
+  })
+}
+
+fn handle_request(
+  document: &DocumentWrapper,
+  processor: &XmlRsProcessor,
+  request: Request,
+  state_manager: &Mutex<StateManager>,
+) -> Response {
+  match request {
+    Request::Hello { capabilities, .. } => hello(&capabilities, None),
+    Request::Batch { requests } => Response::Batch {
+      responses: requests
+        .into_iter()
+        .map(|request| query(document, processor, request))
+        .collect(),
     },
     Request::PutResults { results } => {
+      // Nothing is kept from a batch with an invalid result, since the bake fails regardless.
+      if let Some(err) = results
+        .iter()
+        .find_map(|result| validate_result(result, &document.index).err())
+      {
+        let response = Response::BadRequest {
+          kind: ErrorKind::InvalidResult,
+          reason: format!("InvalidResult: {}", err),
+          details: ErrorDetails {
+            node_id: Some(err.node_id),
+            instruction: err.instruction,
+            ..ErrorDetails::default()
+          },
+        };
+        record_error(state_manager, RequestError::InvalidResult(err));
+        return response;
+      }
       let mut locked_manager = state_manager.lock().unwrap();
       let state_results = &mut locked_manager.results;
       let mut races_in_results = vec![];
       for result in results {
-        if let Some((other_selector, _other_instructions)) = state_results.insert(
-          (result.node_id, result.mode),
-          (result.selector.clone(), result.instructions),
-        ) {
-          races_in_results.push((other_selector, result.selector));
+        let candidates = state_results
+          .entry((result.node_id, result.mode.clone()))
+          .or_default();
+        candidates.push(result);
+        if candidates.len() > 1 {
+          races_in_results.push(Race::new(candidates, 0, candidates.len() - 1));
         }
       }
       locked_manager.races.extend(races_in_results);
       locked_manager.progress += 1;
-      return Ok(());
+      Response::Acknowledged
     }
     Request::PutCount { count } => {
       let mut locked_manager = state_manager.lock().unwrap();
       locked_manager.count += count;
-      return Ok(());
+      Response::Acknowledged
     }
     Request::PutComplete => {
       let mut locked_manager = state_manager.lock().unwrap();
       locked_manager.completed = true;
-      return Ok(());
+      Response::Acknowledged
     }
-    Request::PutError { message } => {
-      return Err(RequestError::ChildTerminated(message));
+    Request::PutError {
+      message,
+      node_id,
+      kind,
+    } => {
+      let message = match node_id {
+        Some(node_id) => match document.location(node_id) {
+          Some(location) => format!("node {} at {}: {}", node_id, location, message),
+          None => format!("node {}: {}", node_id, message),
+        },
+        None => message,
+      };
+      let err = match kind {
+        Some(ErrorKind::SelectorParse)
+        | Some(ErrorKind::UnsupportedCheck)
+        | Some(ErrorKind::BadCheckArgument) => RequestError::TransformSelector(message),
+        _ => RequestError::ChildTerminated(message),
+      };
+      record_error(state_manager, err);
+      Response::Acknowledged
     }
-    Request::HeartBeat => return Ok(()),
-  };
-
-  let response_string = serde_json::to_string(&response)?;
-  let bytes = response_string.as_bytes();
-  stream.write_all(bytes)?;
-
-  Ok(())
+    Request::HeartBeat => Response::Acknowledged,
+    Request::Configure { race_policy } => {
+      let mut locked_manager = state_manager.lock().unwrap();
+      if race_policy.is_some() {
+        locked_manager.race_policy = race_policy;
+      }
+      Response::Acknowledged
+    }
+    request => query(document, processor, request),
+  }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct StateManager {
   count: usize,
-  results: ReplacementMapping,
+  results: Candidates,
   progress: usize,
   completed: bool,
   error: Option<RequestError>,
-  races: HashSet<(String, String)>,
+  races: Vec<Race>,
+  /// The race policy the manifest asks for, if any.
+  race_policy: Option<RacePolicy>,
+}
+
+/// Writes the output next to its destination and moves it into place once it is complete, so
+/// that a failed bake never leaves a partial file behind.
+fn write_output_file(path: &Path, processor: &XmlRsProcessor, queue: &[WriteInstruction]) {
+  let temp_path = path.with_file_name(format!(
+    ".{}.{}.tmp",
+    path
+      .file_name()
+      .map(|name| name.to_string_lossy())
+      .unwrap_or_default(),
+    process::id()
+  ));
+  let output_error = |err: std::io::Error| {
+    (
+      ExitCode::Output,
+      format!("OutputError: {}: {}", path.display(), err),
+    )
+  };
+  let written = fs::File::create(&temp_path)
+    .map_err(output_error)
+    .and_then(|file| {
+      let mut buffered = BufWriter::new(file);
+      processor
+        .write_queue(&mut buffered, queue)
+        .map_err(|err| (ExitCode::Serialization, err.to_string()))?;
+      buffered.flush().map_err(output_error)
+    })
+    .and_then(|()| fs::rename(&temp_path, path).map_err(output_error));
+  if let Err((code, message)) = written {
+    fs::remove_file(&temp_path).ok();
+    exit_with(code, message)
+  }
+}
+
+/// Reports why the bake failed and exits with the code for that kind of failure.
+fn exit_with(code: ExitCode, err: impl fmt::Display) -> ! {
+  eprintln!("Error: {}", Style::new().red().bold().apply_to(err));
+  process::exit(code as i32)
 }
 
-fn unwrap_results(state_manager: Arc<Mutex<StateManager>>) -> ReplacementMapping {
-  Arc::try_unwrap(state_manager)
+/// Exits before any worker has started, removing the socket they would have connected to.
+fn exit_before_start(code: ExitCode, socket_path: &Path, err: impl fmt::Display) -> ! {
+  fs::remove_file(socket_path).ok();
+  exit_with(code, err)
+}
+
+fn unwrap_results(
+  state_manager: Arc<Mutex<StateManager>>,
+  race_policy: RacePolicy,
+) -> ReplacementMapping {
+  let results = Arc::try_unwrap(state_manager)
     .unwrap()
     .into_inner()
     .unwrap()
-    .results
+    .results;
+  resolve_races(results, race_policy)
 }
 
 fn main() {
@@ -589,19 +1140,67 @@ fn main() {
         .default_value("2")
         .help("The number of node workers to use"),
     )
+    .arg(
+      Arg::with_name("encoding")
+        .long("encoding")
+        .short('e')
+        .takes_value(true)
+        .possible_values(&["msgpack", "json"])
+        .default_value("msgpack")
+        .help("How to encode messages to workers that support it; json is easier to debug"),
+    )
+    .arg(
+      Arg::with_name("races")
+        .long("races")
+        .short('r')
+        .takes_value(true)
+        .possible_values(&["fail", "first", "priority", "compose"])
+        .help(
+          "What to do when transforms race for a node, overriding the manifest; defaults to fail",
+        ),
+    )
     .get_matches();
 
+  let node_workers = matches.value_of("node-workers").unwrap();
+  let num_workers: usize = match node_workers.parse() {
+    Ok(num_workers) if num_workers > 0 => num_workers,
+    _ => exit_with(
+      ExitCode::Arguments,
+      format!(
+        "ArgumentError: --node-workers must be a positive whole number, not {:?}",
+        node_workers
+      ),
+    ),
+  };
+
   let mut socket_path = std::env::temp_dir();
   socket_path.push(format!("tmp-baking-{}.sock", rand::random::<u32>()));
-  let listener = UnixListener::bind(&socket_path).unwrap();
-  listener.set_nonblocking(true).unwrap();
+  let socket_error =
+    |err: std::io::Error| format!("SocketError: {}: {}", socket_path.display(), err);
+  let listener = UnixListener::bind(&socket_path)
+    .unwrap_or_else(|err| exit_with(ExitCode::Socket, socket_error(err)));
+  if let Err(err) = listener.set_nonblocking(true) {
+    exit_before_start(ExitCode::Socket, &socket_path, socket_error(err))
+  }
 
   const NUM_STEPS: u8 = 5;
 
-  let baked_file_path =
-    fs::canonicalize(matches.value_of("TARGET").expect("Argument is required")).unwrap();
-  let manifest_file_path =
-    fs::canonicalize(matches.value_of("MANIFEST").expect("Argument is required")).unwrap();
+  let target = matches.value_of("TARGET").expect("Argument is required");
+  let baked_file_path = fs::canonicalize(target).unwrap_or_else(|err| {
+    exit_before_start(
+      ExitCode::Document,
+      &socket_path,
+      format!("DocumentReadError: {}: {}", target, err),
+    )
+  });
+  let manifest = matches.value_of("MANIFEST").expect("Argument is required");
+  let manifest_file_path = fs::canonicalize(manifest).unwrap_or_else(|err| {
+    exit_before_start(
+      ExitCode::Manifest,
+      &socket_path,
+      format!("ManifestReadError: {}: {}", manifest, err),
+    )
+  });
 
   let info_style = Style::new().bold().dim();
 
@@ -610,7 +1209,14 @@ fn main() {
     info_style.apply_to(format!("[1/{}]", NUM_STEPS)),
     style(manifest_file_path.to_string_lossy()).dim()
   );
-  let mut path_to_js = std::env::current_exe().unwrap();
+  let node_error = |err: std::io::Error| -> ! {
+    exit_before_start(
+      ExitCode::Node,
+      &socket_path,
+      format!("NodeStartError: {}", err),
+    )
+  };
+  let mut path_to_js = std::env::current_exe().unwrap_or_else(|err| node_error(err));
   path_to_js.pop();
   path_to_js.push("bake.js");
   let mut child_process = if matches.is_present("node-coverage") {
@@ -622,12 +1228,12 @@ fn main() {
         path_to_js.to_str().unwrap(),
         socket_path.to_str().unwrap(),
         manifest_file_path.to_str().unwrap(),
-        matches.value_of("node-workers").unwrap(),
+        node_workers,
       ])
       .stderr(Stdio::inherit())
       .stdout(Stdio::inherit())
       .spawn()
-      .unwrap()
+      .unwrap_or_else(|err| node_error(err))
   } else {
     Command::new("node")
       .args(&[
@@ -635,12 +1241,12 @@ fn main() {
         path_to_js.to_str().unwrap(),
         socket_path.to_str().unwrap(),
         manifest_file_path.to_str().unwrap(),
-        matches.value_of("node-workers").unwrap(),
+        node_workers,
       ])
       .stderr(Stdio::inherit())
       .stdout(Stdio::inherit())
       .spawn()
-      .unwrap()
+      .unwrap_or_else(|err| node_error(err))
   };
 
   eprintln!(
@@ -651,7 +1257,14 @@ fn main() {
       .dim(),
     style(baked_file_path.to_string_lossy()).dim()
   );
-  let document = parse_file(baked_file_path).unwrap();
+  let document = match parse_file(baked_file_path) {
+    Ok(document) => document,
+    Err(err) => {
+      child_process.kill().ok();
+      fs::remove_file(&socket_path).ok();
+      exit_with(ExitCode::Document, err)
+    }
+  };
 
   let state_manager = Arc::new(Mutex::new(StateManager {
     count: 0,
@@ -659,7 +1272,8 @@ fn main() {
     progress: 0,
     completed: false,
     error: None,
-    races: HashSet::new(),
+    races: vec![],
+    race_policy: None,
   }));
 
   let progress_bar = ProgressBar::hidden();
@@ -673,16 +1287,46 @@ fn main() {
   const CHECK_INTERVAL_MILLIS: u128 = 100;
   let mut last_check = Instant::now();
 
-  scope(|s| {
+  // Every open connection occupies a thread for as long as its worker lives, so the pool needs
+  // room for one per worker, one for the coordinating process and one for this listener on top of
+  // the threads that actually answer requests.
+  let pool = ThreadPoolBuilder::new()
+    .num_threads(rayon::current_num_threads() + num_workers + 2)
+    .build()
+    .unwrap();
+  let connections = Mutex::new(vec![]);
+  let encoding = Encoding::from_name(matches.value_of("encoding").unwrap()).unwrap();
+  // shared with workers asking for the markup of a node, so that it matches the output file
+  let processor = XmlRsProcessor {
+    pad_self_closing: false,
+    perform_indent: matches.is_present("pretty-print"),
+  };
+
+  pool.scope(|s| {
     'listener: for stream in listener.incoming() {
       if let Ok(stream) = stream {
-        s.spawn(|_| {
-          if let Err(err) = handle_request(&document, stream, state_manager.clone()) {
-            let state_manager_clone = state_manager.clone();
-            let mut locked_manager = state_manager_clone.lock().unwrap();
-            if locked_manager.error.is_none() {
-              locked_manager.error = Some(err);
-            }
+        // A connection that can't block can't be read, so the bake fails as if it had broken.
+        if let Err(err) = stream.set_nonblocking(false) {
+          record_error(&state_manager, err.into());
+          continue;
+        }
+        if let Ok(clone) = stream.try_clone() {
+          connections.lock().unwrap().push(clone);
+        }
+        let state_manager = state_manager.clone();
+        let document = &document;
+        let processor = &processor;
+        s.spawn(move |s| {
+          let connection = handle_connection(
+            s,
+            document,
+            processor,
+            stream,
+            encoding,
+            state_manager.clone(),
+          );
+          if let Err(err) = connection {
+            record_error(&state_manager, err);
           }
         });
       }
@@ -707,27 +1351,69 @@ fn main() {
         last_check = now;
       }
     }
+    // Workers may keep their connections open; close them so the scope can finish.
+    for connection in connections.lock().unwrap().iter() {
+      connection.shutdown(Shutdown::Both).ok();
+    }
   });
   eprintln!("{}", style("### Report ###").bold());
   let not_good_style = Style::new().red().bold();
   let good_style = Style::new().green().bold();
+  let race_policy;
+  let failure;
   {
     let locked_manager = state_manager.lock().unwrap();
+    // Races fail the bake unless the command line or the manifest allow them.
+    race_policy = matches
+      .value_of("races")
+      .and_then(RacePolicy::from_name)
+      .or(locked_manager.race_policy)
+      .unwrap_or(RacePolicy::Fail);
+    failure = match locked_manager.error {
+      Some(ref err) => Some(err.exit_code()),
+      None if race_policy == RacePolicy::Fail && !locked_manager.races.is_empty() => {
+        Some(ExitCode::Races)
+      }
+      None => None,
+    };
     eprintln!("Results: {:?}", locked_manager.results.iter().count());
-    eprintln!(
-      "Races: {:?}",
-      if !locked_manager.races.is_empty() {
-        &not_good_style
-      } else {
-        &good_style
+    if locked_manager.races.is_empty() {
+      eprintln!("Races: {}", good_style.apply_to("None"));
+    } else {
+      eprintln!(
+        "Races: {} (race policy {})",
+        not_good_style.apply_to(locked_manager.races.len()),
+        race_policy.name()
+      );
+      let mut races: Vec<&Race> = locked_manager.races.iter().collect();
+      races.sort_by(|a, b| (a.node_id, &a.mode).cmp(&(b.node_id, &b.mode)));
+      for race in races {
+        let location = match document.location(race.node_id) {
+          Some(location) => location.to_string(),
+          None => "unknown location".to_owned(),
+        };
+        eprintln!(
+          "  node {} at {}, mode {:?}:",
+          race.node_id, location, race.mode
+        );
+        let candidates = &locked_manager.results[&(race.node_id, race.mode.clone())];
+        for output in race.outputs.iter() {
+          let instructions = &candidates[output.candidate].instructions;
+          eprintln!(
+            "    transform {} {:?}: {}",
+            output.index,
+            output.selector,
+            document.preview(race.node_id, instructions)
+          );
+        }
       }
-      .apply_to(&locked_manager.races)
-    );
+    }
     match locked_manager.error {
       Some(ref err) => eprintln!("Error: {}", not_good_style.apply_to(err)),
       None => eprintln!("Error: {}", good_style.apply_to("None")),
     };
   }
+  eprintln!("Cache: {}", document.cache);
 
   eprintln!(
     "{} Shutting down acceptor...",
@@ -742,6 +1428,9 @@ fn main() {
     child_process.kill().unwrap();
   }
   fs::remove_file(socket_path).ok();
+  if let Some(code) = failure {
+    exit_with(code, "the bake failed, so nothing was written")
+  }
 
   eprintln!(
     "{} Serializing...",
@@ -750,23 +1439,377 @@ fn main() {
       .bold()
       .dim(),
   );
-  let results = unwrap_results(state_manager);
-  let write_instruction_queue = document.to_write_instruction_queue(&results);
-  let processor = XmlRsProcessor {
-    pad_self_closing: false,
-    perform_indent: matches.is_present("pretty-print"),
-  };
-  let writer: Box<dyn Write> = match matches.value_of("OUTFILE") {
-    Some(file) => Box::new(fs::File::create(file).unwrap()),
-    None => Box::new(std::io::stdout()),
-  };
-  let buffered = BufWriter::new(writer);
-  processor
-    .write_queue(buffered, &write_instruction_queue)
-    .unwrap();
+  let results = unwrap_results(state_manager, race_policy);
+  let write_instruction_queue = document
+    .to_write_instruction_queue(&results)
+    .unwrap_or_else(|err| exit_with(ExitCode::Serialization, err));
+  match matches.value_of("OUTFILE") {
+    Some(file) => write_output_file(Path::new(file), &processor, &write_instruction_queue),
+    None => {
+      let stdout = std::io::stdout();
+      let mut buffered = BufWriter::new(stdout.lock());
+      if let Err(err) = processor.write_queue(&mut buffered, &write_instruction_queue) {
+        exit_with(ExitCode::Serialization, err)
+      }
+      if let Err(err) = buffered.flush() {
+        exit_with(ExitCode::Output, format!("OutputError: {}", err))
+      }
+    }
+  }
 
   eprintln!("{}", style("Done!").green().bold());
 }
 
 #[test]
 fn passing_test() {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn serialize(xml: &str, id: usize, inner: bool) -> String {
+    let document = DocumentWrapper::new(xml.to_owned()).unwrap();
+    let processor = XmlRsProcessor {
+      pad_self_closing: false,
+      perform_indent: false,
+    };
+    document.serialize(id, inner, &processor).unwrap()
+  }
+
+  #[test]
+  fn standalone_nodes_declare_every_namespace_in_scope_once() {
+    let xml = r#"<a xmlns="urn:a" xmlns:m="urn:m"><m:b xmlns:n="urn:n" n:x="1"><c/></m:b></a>"#;
+    let document = Document::parse(xml).unwrap();
+    let b = document.get_node(2usize.into()).unwrap();
+    let queue = queue_standalone(&document, b).unwrap();
+    let declared: Vec<Vec<String>> = queue
+      .iter()
+      .take_while(|instruction| !matches!(instruction, WriteInstruction::EndElement { .. }))
+      .filter_map(|instruction| match instruction {
+        WriteInstruction::Namespaces { namespaces } => Some(
+          namespaces
+            .iter()
+            .map(|namespace| namespace.uri.clone())
+            .collect(),
+        ),
+        _ => None,
+      })
+      .collect();
+    // `c` declares nothing of its own.
+    assert_eq!(declared.len(), 2);
+    for uri in &["urn:a", "urn:m", "urn:n"] {
+      let declarations = declared[0].iter().filter(|declared| declared == uri);
+      assert_eq!(declarations.count(), 1, "{:?}", declared);
+    }
+    assert!(declared[1].is_empty());
+    let outer = serialize(xml, 2, false);
+    assert_eq!(outer.matches(r#"xmlns:n="urn:n""#).count(), 1, "{}", outer);
+  }
+
+  #[test]
+  fn missing_operands_report_their_position() {
+    let response = Response::from(QueryError::Selector(SelectorError::MissingOperand {
+      offset: 4,
+    }));
+    let encoded = serde_json::to_value(&response).unwrap();
+    assert_eq!(encoded["B"]["k"], "selector-parse");
+    assert_eq!(encoded["B"]["d"], serde_json::json!({ "o": 4 }));
+  }
+
+  #[test]
+  fn transforms_failing_on_selectors_exit_with_their_own_code() {
+    let document = DocumentWrapper::new("<a/>".to_owned()).unwrap();
+    let processor = XmlRsProcessor {
+      pad_self_closing: false,
+      perform_indent: false,
+    };
+    let report = |kind| {
+      let state_manager = Mutex::new(StateManager::default());
+      let request = Request::PutError {
+        message: "failed".to_owned(),
+        node_id: Some(0),
+        kind,
+      };
+      handle_request(&document, &processor, request, &state_manager);
+      let error = state_manager.into_inner().unwrap().error.unwrap();
+      error.exit_code()
+    };
+    assert_eq!(report(Some(ErrorKind::SelectorParse)), ExitCode::Selector);
+    assert_eq!(
+      report(Some(ErrorKind::BadCheckArgument)),
+      ExitCode::Selector
+    );
+    assert_eq!(report(Some(ErrorKind::UnknownNode)), ExitCode::Worker);
+    assert_eq!(report(None), ExitCode::Worker);
+  }
+
+  #[test]
+  fn check_errors_report_their_step_and_function() {
+    let response = Response::from(QueryError::Selector(SelectorError::CheckError {
+      step: 1,
+      function: "foo".to_owned(),
+      reason: CheckErrorReason::UnknownFunction,
+    }));
+    let encoded = serde_json::to_value(&response).unwrap();
+    assert_eq!(encoded["B"]["k"], "unsupported-check");
+    assert_eq!(encoded["B"]["d"], serde_json::json!({ "s": 1, "f": "foo" }));
+    let response = Response::from(QueryError::Selector(SelectorError::CheckError {
+      step: 0,
+      function: "nth".to_owned(),
+      reason: CheckErrorReason::BadArgument {
+        index: 0,
+        expected: "a positive integer",
+      },
+    }));
+    let encoded = serde_json::to_value(&response).unwrap();
+    assert_eq!(encoded["B"]["k"], "bad-check-argument");
+    assert_eq!(encoded["B"]["d"], serde_json::json!({ "s": 0, "f": "nth" }));
+  }
+
+  /// Handles one request against `xml`, returning the response as the worker would decode it.
+  fn answer(xml: &str, request: serde_json::Value) -> serde_json::Value {
+    let document = DocumentWrapper::new(xml.to_owned()).unwrap();
+    let processor = XmlRsProcessor {
+      pad_self_closing: false,
+      perform_indent: false,
+    };
+    let request = serde_json::from_value(request).unwrap();
+    let state_manager = Mutex::new(StateManager::default());
+    let response = handle_request(&document, &processor, request, &state_manager);
+    serde_json::to_value(&response).unwrap()
+  }
+
+  #[test]
+  fn batched_requests_fail_on_their_own() {
+    let batch = serde_json::json!({ "Q": { "r": [
+      { "T": { "n": 1 } },
+      { "T": { "n": 99 } },
+      { "A": { "n": 1 } },
+      "H",
+      { "C": { "c": 1 } },
+    ] } });
+    let responses = &answer(r#"<a x="1">b</a>"#, batch)["Q"]["r"];
+    assert_eq!(responses[0]["T"]["t"], "b");
+    assert_eq!(responses[1]["B"]["k"], "unknown-node");
+    assert_eq!(responses[1]["B"]["d"]["n"], 99);
+    assert_eq!(responses[2]["A"]["a"][0]["v"], "1");
+    for response in &[&responses[3], &responses[4]] {
+      assert_eq!(response["B"]["k"], "not-batchable");
+    }
+    assert_eq!(responses.as_array().unwrap().len(), 5);
+  }
+
+  #[test]
+  fn descriptors_hold_the_fields_asked_for() {
+    let xml = r#"<r xmlns:m="urn:m"><a/><m:b k="v">x<c>y</c></m:b></r>"#;
+    let describe = |fields: &str| {
+      let fields: Vec<String> = fields.chars().map(String::from).collect();
+      let request = serde_json::json!({ "D": { "n": 3, "f": fields } });
+      answer(xml, request)["D"]["d"].clone()
+    };
+    let expected = serde_json::json!({
+      "q": { "u": "urn:m", "l": "b" },
+      "k": "e",
+      "a": [{ "q": { "u": "", "l": "k" }, "v": "v" }],
+      "p": 1,
+      "c": [4, 5],
+      "o": "x",
+      "t": "xy",
+      "d": 2,
+      "i": 1,
+    });
+    for (field, value) in expected.as_object().unwrap() {
+      let descriptor = describe(field);
+      assert_eq!(descriptor["n"], 3);
+      assert_eq!(&descriptor[field], value, "{}", field);
+      assert_eq!(descriptor.as_object().unwrap().len(), 2, "{}", descriptor);
+    }
+    let namespaces = describe("s")["s"].clone();
+    assert!(namespaces
+      .as_array()
+      .unwrap()
+      .iter()
+      .any(|namespace| namespace["p"] == "m" && namespace["u"] == "urn:m"));
+    let everything = answer(xml, serde_json::json!({ "D": { "n": 0 } }))["D"]["d"].clone();
+    assert_eq!(everything["k"], "r");
+    assert_eq!(everything["p"], serde_json::Value::Null);
+    assert_eq!(
+      everything.as_object().unwrap().len(),
+      DescriptorField::ALL.len() + 1
+    );
+  }
+
+  #[test]
+  fn locations_count_lines_and_characters() {
+    let xml = "<r>\n  <a/>\n\t<é/><b>\n    <c/></b>\n</r>";
+    let location = |id: usize| {
+      let location = answer(xml, serde_json::json!({ "L": { "n": id } }))["L"]["o"].clone();
+      (location["l"].clone(), location["c"].clone())
+    };
+    let document = Document::parse(xml).unwrap();
+    let id = |name: &str| {
+      document
+        .descendants()
+        .find(|node| node.has_tag_name(name))
+        .unwrap()
+        .id()
+        .get_usize()
+    };
+    assert_eq!(location(id("r")), (1.into(), 1.into()));
+    assert_eq!(location(id("a")), (2.into(), 3.into()));
+    assert_eq!(location(id("b")), (3.into(), 6.into()));
+    assert_eq!(location(id("c")), (4.into(), 5.into()));
+    assert_eq!(
+      answer(xml, serde_json::json!({ "L": { "n": 99 } }))["B"]["k"],
+      "unknown-node"
+    );
+  }
+
+  /// Bakes `xml` with the given transform outputs, each a node id, its selector and its
+  /// instructions in the default mode.
+  fn bake(
+    xml: &str,
+    outputs: Vec<(usize, &str, Vec<WriteInstruction>)>,
+  ) -> Result<String, SerializationError> {
+    let document = DocumentWrapper::new(xml.to_owned()).unwrap();
+    let processor = XmlRsProcessor {
+      pad_self_closing: false,
+      perform_indent: false,
+    };
+    let mapping: ReplacementMapping = outputs
+      .into_iter()
+      .map(|(node_id, selector, instructions)| {
+        (
+          (node_id, "default".to_owned()),
+          (selector.to_owned(), instructions),
+        )
+      })
+      .collect();
+    let queue = document.to_write_instruction_queue(&mapping)?;
+    let mut buffer = vec![];
+    processor.write_queue(&mut buffer, &queue)?;
+    Ok(String::from_utf8(buffer).unwrap())
+  }
+
+  fn replace(node_id: usize) -> WriteInstruction {
+    WriteInstruction::Replace {
+      node_id,
+      mode: "default".to_owned(),
+    }
+  }
+
+  #[test]
+  fn replacing_a_missing_node_names_the_transform() {
+    match bake("<a><b/></a>", vec![(2, "//b", vec![replace(99)])]) {
+      Err(SerializationError::UnknownNode { node_id, selector }) => {
+        assert_eq!((node_id, selector.as_str()), (99, "//b"))
+      }
+      other => panic!("unexpected result {:?}", other),
+    }
+  }
+
+  #[test]
+  fn relations_follow_document_order_and_nesting() {
+    let xml = "<r><s><s><p/></s></s><q/></r>";
+    let relation = |request: serde_json::Value| answer(xml, request);
+    let order = |a: usize, b: usize| {
+      relation(serde_json::json!({ "O": { "a": a, "b": b } }))["O"]["o"].clone()
+    };
+    assert_eq!(order(2, 4), -1);
+    assert_eq!(order(4, 4), 0);
+    assert_eq!(order(5, 3), 1);
+    let is_ancestor = |a: usize, n: usize| {
+      relation(serde_json::json!({ "AN": { "a": a, "n": n } }))["AN"]["r"].clone()
+    };
+    assert_eq!(is_ancestor(2, 4), true);
+    assert_eq!(is_ancestor(0, 5), true);
+    assert_eq!(is_ancestor(4, 4), false);
+    assert_eq!(is_ancestor(4, 2), false);
+    assert_eq!(is_ancestor(2, 5), false);
+    assert_eq!(
+      relation(serde_json::json!({ "AN": { "a": 99, "n": 1 } }))["B"]["k"],
+      "unknown-node"
+    );
+  }
+
+  #[test]
+  fn closest_skips_the_node_itself() {
+    use crate::select::ActionableSelector;
+    use scandent::{Axis, NameRequirement, Predicate, Selector, Step};
+    let document = DocumentWrapper::new("<r><s><s><p/></s></s></r>".to_owned()).unwrap();
+    let step = Step {
+      axis: Axis::Descendant,
+      predicate: Predicate {
+        name: NameRequirement {
+          localname: Some("s".to_owned()),
+          namespace: None,
+        },
+        attributes: vec![],
+        paths: vec![],
+        checks: vec![],
+      },
+    };
+    let selector = ActionableSelector::from_selector(Selector { steps: vec![step] }).unwrap();
+    let s = SelectorExpression::Path(selector);
+    assert_eq!(document.closest(4, &s).unwrap(), Some(3));
+    assert_eq!(document.closest(3, &s).unwrap(), Some(2));
+    assert_eq!(document.closest(2, &s).unwrap(), None);
+    assert!(matches!(
+      document.closest(99, &s),
+      Err(QueryError::UnknownNode(99))
+    ));
+  }
+
+  const SIBLINGS: &str = "<r><a/><b/><c/></r>";
+
+  #[test]
+  fn replace_cycles_report_the_whole_chain() {
+    let err = bake(
+      SIBLINGS,
+      vec![(2, "//a", vec![replace(3)]), (3, "//b", vec![replace(2)])],
+    )
+    .unwrap_err();
+    match &err {
+      SerializationError::ReplaceCycle(chain) => {
+        let steps: Vec<(usize, &str)> = chain
+          .iter()
+          .map(|step| (step.node_id, step.selector.as_str()))
+          .collect();
+        assert_eq!(steps, [(2, "//a"), (3, "//b")]);
+      }
+      other => panic!("unexpected error {:?}", other),
+    }
+    assert_eq!(
+      err.to_string(),
+      "ReplaceCycle: node 2 in mode \"default\" (transform \"//a\") replaces node 3 in mode \
+       \"default\" (transform \"//b\"), which replaces node 2 in mode \"default\" again"
+    );
+  }
+
+  #[test]
+  fn nodes_replacing_themselves_are_cycles() {
+    let err = bake(SIBLINGS, vec![(2, "//a", vec![replace(2)])]).unwrap_err();
+    assert!(matches!(&err, SerializationError::ReplaceCycle(chain) if chain.len() == 1));
+    assert_eq!(
+      err.to_string(),
+      "ReplaceCycle: node 2 in mode \"default\" (transform \"//a\") replaces itself"
+    );
+  }
+
+  #[test]
+  fn nodes_replaced_from_several_places_are_not_cycles() {
+    let text = WriteInstruction::Text {
+      text: "x".to_owned(),
+    };
+    let baked = bake(
+      SIBLINGS,
+      vec![
+        (2, "//a", vec![replace(4)]),
+        (3, "//b", vec![replace(4)]),
+        (4, "//c", vec![text]),
+      ],
+    )
+    .unwrap();
+    assert_eq!(baked, "<r>xxx</r>");
+  }
+}
@@ -0,0 +1,345 @@
+use crate::error::{RequestError, RequestResult};
+use crate::schema::{Encoding, ErrorDetails, ErrorKind, Request, Response};
+use std::io::{self, BufRead, Read, Write};
+
+/// Bumped whenever requests or responses change shape. Workers have to speak exactly this
+/// version.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of the protocol this engine supports.
+pub(crate) const CAPABILITIES: &[&str] = &[
+  "batch",
+  "describe",
+  "serialize",
+  "location",
+  "matches",
+  "relations",
+  "configure",
+];
+
+impl Encoding {
+  pub(crate) fn from_name(name: &str) -> Option<Encoding> {
+    match name {
+      "json" => Some(Encoding::Json),
+      "msgpack" => Some(Encoding::MessagePack),
+      _ => None,
+    }
+  }
+
+  fn decode(self, payload: &[u8]) -> RequestResult<Request> {
+    Ok(match self {
+      Encoding::Json => serde_json::from_slice(payload)?,
+      Encoding::MessagePack => rmp_serde::from_slice(payload)?,
+    })
+  }
+
+  fn encode(self, response: &Response) -> RequestResult<Vec<u8>> {
+    Ok(match self {
+      Encoding::Json => serde_json::to_vec(response)?,
+      // Named fields, so that both encodings share the keys in schema.rs.
+      Encoding::MessagePack => rmp_serde::to_vec_named(response)?,
+    })
+  }
+}
+
+/// The largest payload the engine will read or write. A bigger request is skipped and a bigger
+/// response is not sent; either way the request is answered with a `payload-too-large` error.
+pub(crate) const MAX_PAYLOAD_LENGTH: usize = 512 * 1024 * 1024;
+
+/// What arrived in a frame. Frames that can't be turned into a request are still answered, since
+/// their id is known from the header.
+pub(crate) enum Incoming {
+  Request(Request),
+  Malformed(RequestError),
+  TooLarge(usize),
+}
+
+/// Reads one frame: a header of two big-endian `u32`s, the payload length and the request id,
+/// followed by the payload in the connection's encoding. Returns `None` when the connection closes
+/// cleanly between frames.
+pub(crate) fn read_frame<R: Read>(
+  reader: &mut R,
+  encoding: Encoding,
+) -> RequestResult<Option<(u32, Incoming)>> {
+  let mut header = [0; 8];
+  match reader.read_exact(&mut header) {
+    Ok(()) => {}
+    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(err) => return Err(err.into()),
+  }
+  let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+  let id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+  if length > MAX_PAYLOAD_LENGTH {
+    io::copy(&mut reader.take(length as u64), &mut io::sink())?;
+    return Ok(Some((id, Incoming::TooLarge(length))));
+  }
+  let mut payload = vec![0; length];
+  reader.read_exact(&mut payload)?;
+  Ok(Some((
+    id,
+    match encoding.decode(&payload) {
+      Ok(request) => Incoming::Request(request),
+      Err(err) => Incoming::Malformed(err),
+    },
+  )))
+}
+
+/// Writes the response to the request with the given id, framed the same way as requests. A
+/// response larger than a worker will read is answered with a `payload-too-large` error instead.
+pub(crate) fn write_frame<W: Write>(
+  writer: &mut W,
+  id: u32,
+  response: &Response,
+  encoding: Encoding,
+) -> RequestResult<()> {
+  write_frame_within(writer, id, response, encoding, MAX_PAYLOAD_LENGTH)
+}
+
+fn write_frame_within<W: Write>(
+  writer: &mut W,
+  id: u32,
+  response: &Response,
+  encoding: Encoding,
+  limit: usize,
+) -> RequestResult<()> {
+  let mut payload = encoding.encode(response)?;
+  if payload.len() > limit {
+    payload = encoding.encode(&Response::BadRequest {
+      kind: ErrorKind::PayloadTooLarge,
+      reason: format!(
+        "PayloadTooLarge: response of {} bytes exceeds the limit of {} bytes",
+        payload.len(),
+        limit
+      ),
+      details: ErrorDetails {
+        limit: Some(limit),
+        ..ErrorDetails::default()
+      },
+    })?;
+  }
+  let mut bytes = Vec::with_capacity(payload.len() + 8);
+  bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+  bytes.extend_from_slice(&id.to_be_bytes());
+  bytes.extend_from_slice(&payload);
+  writer.write_all(&bytes)?;
+  Ok(())
+}
+
+/// The answer to a `Hello`: the capabilities both sides support, and for the opening `Hello` the
+/// encoding of the rest of the connection.
+pub(crate) fn hello(capabilities: &[String], encoding: Option<Encoding>) -> Response {
+  Response::Hello {
+    version: PROTOCOL_VERSION,
+    capabilities: CAPABILITIES
+      .iter()
+      .filter(|capability| capabilities.iter().any(|other| other == *capability))
+      .map(|capability| capability.to_string())
+      .collect(),
+    encoding,
+  }
+}
+
+/// Every connection has to open with a `Hello` naming the protocol version the worker speaks.
+/// The rest of the connection uses the preferred encoding if the worker can speak it, and JSON
+/// otherwise. Returns `None` if the connection closed without a word.
+pub(crate) fn handshake<R: BufRead, W: Write>(
+  reader: &mut R,
+  writer: &mut W,
+  preferred: Encoding,
+) -> RequestResult<Option<Encoding>> {
+  match reader.fill_buf()?.first() {
+    None => return Ok(None),
+    // Releases before framing wrote bare JSON.
+    Some(b'{') => {
+      return Err(RequestError::IncompatibleWorker(
+        "the worker sent an unframed request, so it is from an older release than the engine"
+          .to_owned(),
+      ))
+    }
+    Some(_) => {}
+  }
+  let (id, incoming) = match read_frame(reader, Encoding::Json)? {
+    Some(frame) => frame,
+    None => return Ok(None),
+  };
+  match incoming {
+    Incoming::Request(Request::Hello {
+      version,
+      capabilities,
+      encodings,
+    }) if version == PROTOCOL_VERSION => {
+      let encoding = if encodings
+        .iter()
+        .any(|name| Encoding::from_name(name) == Some(preferred))
+      {
+        preferred
+      } else {
+        Encoding::Json
+      };
+      write_frame(
+        writer,
+        id,
+        &hello(&capabilities, Some(encoding)),
+        Encoding::Json,
+      )?;
+      Ok(Some(encoding))
+    }
+    Incoming::Request(Request::Hello { version, .. }) => refuse(
+      writer,
+      id,
+      format!(
+        "the worker speaks protocol version {} but the engine speaks version {}",
+        version, PROTOCOL_VERSION
+      ),
+    ),
+    _ => refuse(
+      writer,
+      id,
+      "the worker did not open with a Hello request, so it is probably from an older release"
+        .to_owned(),
+    ),
+  }
+}
+
+fn refuse<W: Write>(writer: &mut W, id: u32, message: String) -> RequestResult<Option<Encoding>> {
+  let response = Response::BadRequest {
+    kind: ErrorKind::ProtocolVersion,
+    reason: format!("ProtocolVersion: {}", message),
+    details: ErrorDetails {
+      version: Some(PROTOCOL_VERSION),
+      ..ErrorDetails::default()
+    },
+  };
+  // The worker is refused either way, so a failed write changes nothing.
+  write_frame(writer, id, &response, Encoding::Json).ok();
+  Err(RequestError::IncompatibleWorker(message))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn frame(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(&id.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+  }
+
+  fn read(reader: &mut impl Read) -> (u32, Incoming) {
+    read_frame(reader, Encoding::Json).unwrap().unwrap()
+  }
+
+  #[test]
+  fn malformed_frames_are_answered_and_reading_goes_on() {
+    let mut bytes = frame(1, br#"{"T":{"n":3}}"#);
+    bytes.extend(frame(2, b"{nope"));
+    bytes.extend(frame(3, br#"{"T":{"n":"three"}}"#));
+    bytes.extend(frame(4, br#"{"T":{"n":4}}"#));
+    let mut reader = Cursor::new(bytes);
+    assert!(matches!(
+      read(&mut reader),
+      (1, Incoming::Request(Request::Text { node_id: 3 }))
+    ));
+    assert!(matches!(
+      read(&mut reader),
+      (2, Incoming::Malformed(RequestError::JsonParseError(_)))
+    ));
+    assert!(matches!(
+      read(&mut reader),
+      (3, Incoming::Malformed(RequestError::JsonParseError(_)))
+    ));
+    assert!(matches!(
+      read(&mut reader),
+      (4, Incoming::Request(Request::Text { node_id: 4 }))
+    ));
+    assert!(read_frame(&mut reader, Encoding::Json).unwrap().is_none());
+  }
+
+  #[test]
+  fn oversized_frames_are_skipped_whole() {
+    let length = MAX_PAYLOAD_LENGTH + 1;
+    let mut header = (length as u32).to_be_bytes().to_vec();
+    header.extend_from_slice(&5u32.to_be_bytes());
+    let mut reader = Cursor::new(header)
+      .chain(io::repeat(b' ').take(length as u64))
+      .chain(Cursor::new(frame(6, br#"{"T":{"n":1}}"#)));
+    assert!(matches!(read(&mut reader), (5, Incoming::TooLarge(found)) if found == length));
+    assert!(matches!(
+      read(&mut reader),
+      (6, Incoming::Request(Request::Text { node_id: 1 }))
+    ));
+  }
+
+  #[test]
+  fn frames_cut_short_are_connection_errors() {
+    let bytes = frame(1, br#"{"T":{"n":3}}"#);
+    let mut reader = Cursor::new(&bytes[..bytes.len() - 1]);
+    assert!(read_frame(&mut reader, Encoding::Json).is_err());
+  }
+
+  #[test]
+  fn responses_are_framed_like_requests() {
+    let mut bytes = vec![];
+    write_frame(&mut bytes, 7, &Response::Acknowledged, Encoding::Json).unwrap();
+    assert_eq!(bytes, frame(7, br#""K""#));
+  }
+
+  #[test]
+  fn oversized_responses_are_answered_with_an_error() {
+    let text = Response::Text {
+      text: "x".repeat(100),
+    };
+    let mut bytes = vec![];
+    write_frame_within(&mut bytes, 8, &text, Encoding::Json, 100).unwrap();
+    let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    assert_eq!(length, bytes.len() - 8);
+    assert_eq!(&bytes[4..8], &8u32.to_be_bytes());
+    let response: serde_json::Value = serde_json::from_slice(&bytes[8..]).unwrap();
+    assert_eq!(response["B"]["k"], "payload-too-large");
+    assert_eq!(response["B"]["d"]["l"], 100);
+  }
+
+  fn refusal(bytes: &[u8]) -> (RequestError, serde_json::Value) {
+    let mut written = vec![];
+    let err = handshake(&mut &bytes[..], &mut written, Encoding::Json).unwrap_err();
+    let response = serde_json::from_slice(&written[8..]).unwrap();
+    (err, response)
+  }
+
+  #[test]
+  fn handshakes_agree_on_shared_capabilities() {
+    let mut written = vec![];
+    let bytes = frame(3, br#"{"HI":{"v":1,"c":["batch","teleport"]}}"#);
+    let encoding = handshake(&mut &bytes[..], &mut written, Encoding::Json).unwrap();
+    assert_eq!(encoding, Some(Encoding::Json));
+    assert_eq!(
+      written,
+      frame(3, br#"{"HI":{"v":1,"c":["batch"],"e":"json"}}"#)
+    );
+  }
+
+  #[test]
+  fn handshakes_refuse_other_versions_and_missing_hellos() {
+    let (err, response) = refusal(&frame(1, br#"{"HI":{"v":0}}"#));
+    assert!(matches!(err, RequestError::IncompatibleWorker(_)));
+    assert_eq!(response["B"]["k"], "protocol-version");
+    assert_eq!(response["B"]["d"]["v"], PROTOCOL_VERSION);
+    let (err, response) = refusal(&frame(1, br#"{"T":{"n":1}}"#));
+    assert!(matches!(err, RequestError::IncompatibleWorker(_)));
+    assert_eq!(response["B"]["k"], "protocol-version");
+    let (err, _) = refusal(&frame(1, b"{nope"));
+    assert!(matches!(err, RequestError::IncompatibleWorker(_)));
+  }
+
+  #[test]
+  fn handshakes_reject_unframed_requests_without_answering() {
+    let mut written = vec![];
+    let err = handshake(&mut &br#"{"T":{"n":1}}"#[..], &mut written, Encoding::Json).unwrap_err();
+    assert!(matches!(err, RequestError::IncompatibleWorker(_)));
+    assert!(written.is_empty());
+    let closed = handshake(&mut &b""[..], &mut written, Encoding::Json).unwrap();
+    assert!(closed.is_none());
+  }
+}
@@ -0,0 +1,183 @@
+use crate::schema::{RacePolicy, TransformResult};
+use crate::ReplacementMapping;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+/// Every result received for a node and mode, in the order they arrived.
+pub(crate) type Candidates = HashMap<(usize, String), Vec<TransformResult>>;
+
+/// Two transforms that produced output for the same node in the same mode, ordered by where they
+/// are declared. Their outputs stay in `Candidates` until the bake is over.
+#[derive(Debug)]
+pub(crate) struct Race {
+  pub(crate) node_id: usize,
+  pub(crate) mode: String,
+  pub(crate) outputs: [RaceOutput; 2],
+}
+
+#[derive(Debug)]
+pub(crate) struct RaceOutput {
+  pub(crate) selector: String,
+  pub(crate) index: usize,
+  /// Where the output is among the candidates for its node and mode.
+  pub(crate) candidate: usize,
+}
+
+impl Race {
+  pub(crate) fn new(candidates: &[TransformResult], first: usize, second: usize) -> Race {
+    let output = |candidate: usize| RaceOutput {
+      selector: candidates[candidate].selector.clone(),
+      index: candidates[candidate].index,
+      candidate,
+    };
+    let (first, second) = if candidates[second].index < candidates[first].index {
+      (second, first)
+    } else {
+      (first, second)
+    };
+    Race {
+      node_id: candidates[first].node_id,
+      mode: candidates[first].mode.clone(),
+      outputs: [output(first), output(second)],
+    }
+  }
+}
+
+impl RacePolicy {
+  pub(crate) fn from_name(name: &str) -> Option<RacePolicy> {
+    match name {
+      "fail" => Some(RacePolicy::Fail),
+      "first" => Some(RacePolicy::First),
+      "priority" => Some(RacePolicy::Priority),
+      "compose" => Some(RacePolicy::Compose),
+      _ => None,
+    }
+  }
+
+  pub(crate) fn name(self) -> &'static str {
+    match self {
+      RacePolicy::Fail => "fail",
+      RacePolicy::First => "first",
+      RacePolicy::Priority => "priority",
+      RacePolicy::Compose => "compose",
+    }
+  }
+}
+
+/// Picks the output written for each node and mode. Only the declaration order of transforms and
+/// their priorities matter, never the order their results arrived in. Under `RacePolicy::Fail`
+/// there is nothing to pick once the bake has gone ahead, so it behaves like `RacePolicy::First`.
+pub(crate) fn resolve_races(candidates: Candidates, policy: RacePolicy) -> ReplacementMapping {
+  candidates
+    .into_iter()
+    .map(|(key, mut results)| {
+      match policy {
+        RacePolicy::Priority => {
+          results.sort_by_key(|result| (Reverse(result.priority), result.index))
+        }
+        _ => results.sort_by_key(|result| result.index),
+      }
+      let output = if policy == RacePolicy::Compose {
+        let selectors: Vec<&str> = results
+          .iter()
+          .map(|result| result.selector.as_str())
+          .collect();
+        (
+          selectors.join(", "),
+          results
+            .iter()
+            .flat_map(|result| result.instructions.iter().cloned())
+            .collect(),
+        )
+      } else {
+        let winner = results.swap_remove(0);
+        (winner.selector, winner.instructions)
+      };
+      (key, output)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::schema::WriteInstruction;
+
+  fn result(selector: &str, index: usize, priority: i64) -> TransformResult {
+    TransformResult {
+      node_id: 1,
+      mode: "default".to_owned(),
+      selector: selector.to_owned(),
+      instructions: vec![WriteInstruction::Text {
+        text: selector.to_owned(),
+      }],
+      index,
+      priority,
+    }
+  }
+
+  /// Resolves results that arrived in the given order, returning the selector written and the text
+  /// of its instructions.
+  fn resolve(results: Vec<TransformResult>, policy: RacePolicy) -> (String, Vec<String>) {
+    let mut candidates = Candidates::new();
+    candidates.insert((1, "default".to_owned()), results);
+    let (selector, instructions) = resolve_races(candidates, policy)
+      .remove(&(1, "default".to_owned()))
+      .unwrap();
+    let texts = instructions
+      .into_iter()
+      .map(|instruction| match instruction {
+        WriteInstruction::Text { text } => text,
+        other => panic!("unexpected instruction {:?}", other),
+      })
+      .collect();
+    (selector, texts)
+  }
+
+  fn raced() -> Vec<TransformResult> {
+    vec![result("c", 2, 0), result("a", 0, 1), result("b", 1, 5)]
+  }
+
+  #[test]
+  fn the_transform_declared_first_wins() {
+    for policy in [RacePolicy::First, RacePolicy::Fail].iter() {
+      assert_eq!(
+        resolve(raced(), *policy),
+        ("a".to_owned(), vec!["a".to_owned()])
+      );
+    }
+  }
+
+  #[test]
+  fn the_highest_priority_wins_then_the_transform_declared_first() {
+    assert_eq!(
+      resolve(raced(), RacePolicy::Priority),
+      ("b".to_owned(), vec!["b".to_owned()])
+    );
+    let tied = vec![result("c", 2, 5), result("b", 1, 5), result("a", 0, 1)];
+    assert_eq!(
+      resolve(tied, RacePolicy::Priority),
+      ("b".to_owned(), vec!["b".to_owned()])
+    );
+  }
+
+  #[test]
+  fn composed_outputs_are_written_in_declaration_order() {
+    assert_eq!(
+      resolve(raced(), RacePolicy::Compose),
+      (
+        "a, b, c".to_owned(),
+        vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]
+      )
+    );
+  }
+
+  #[test]
+  fn races_order_their_outputs_by_declaration() {
+    let race = Race::new(&raced(), 0, 1);
+    assert_eq!(race.outputs[0].selector, "a");
+    assert_eq!(race.outputs[0].candidate, 1);
+    assert_eq!(race.outputs[1].selector, "c");
+    assert_eq!(race.outputs[1].candidate, 0);
+  }
+}
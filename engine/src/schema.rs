@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct QualifiedName {
@@ -8,12 +9,177 @@ pub(crate) struct QualifiedName {
   pub(crate) local_name: String,
 }
 
+#[derive(Serialize, Debug, Clone, Copy)]
+pub(crate) struct Location {
+  #[serde(rename = "l")]
+  pub(crate) line: usize,
+  #[serde(rename = "c")]
+  pub(crate) column: usize,
+}
+
+impl fmt::Display for Location {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}, column {}", self.line, self.column)
+  }
+}
+
 #[derive(Serialize, Debug)]
 pub(crate) struct Element {
   #[serde(rename = "q")]
   pub(crate) qualified_name: QualifiedName,
   #[serde(rename = "n")]
   pub(crate) node_id: usize,
+  #[serde(rename = "o", skip_serializing_if = "Option::is_none")]
+  pub(crate) location: Option<Location>,
+  #[serde(rename = "d", skip_serializing_if = "Option::is_none")]
+  pub(crate) descriptor: Option<NodeDescriptor>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub(crate) enum NodeKind {
+  #[serde(rename = "r")]
+  Root,
+  #[serde(rename = "e")]
+  Element,
+  #[serde(rename = "t")]
+  Text,
+  #[serde(rename = "c")]
+  Comment,
+  #[serde(rename = "p")]
+  ProcessingInstruction,
+}
+
+/// Selects a field of a `NodeDescriptor`. Each is named after the key of the field it selects.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DescriptorField {
+  #[serde(rename = "q")]
+  QualifiedName,
+  #[serde(rename = "k")]
+  Kind,
+  #[serde(rename = "a")]
+  Attributes,
+  #[serde(rename = "s")]
+  Namespaces,
+  #[serde(rename = "p")]
+  Parent,
+  #[serde(rename = "c")]
+  Children,
+  #[serde(rename = "o")]
+  OwnText,
+  #[serde(rename = "t")]
+  Text,
+  #[serde(rename = "d")]
+  Depth,
+  #[serde(rename = "i")]
+  SiblingIndex,
+}
+
+impl DescriptorField {
+  pub(crate) const ALL: [DescriptorField; 10] = [
+    DescriptorField::QualifiedName,
+    DescriptorField::Kind,
+    DescriptorField::Attributes,
+    DescriptorField::Namespaces,
+    DescriptorField::Parent,
+    DescriptorField::Children,
+    DescriptorField::OwnText,
+    DescriptorField::Text,
+    DescriptorField::Depth,
+    DescriptorField::SiblingIndex,
+  ];
+}
+
+/// Everything a worker usually wants to know about a node. Fields that weren't asked for are
+/// left out; `parent` is `null` for the root.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct NodeDescriptor {
+  #[serde(rename = "n")]
+  pub(crate) node_id: usize,
+  #[serde(rename = "q", skip_serializing_if = "Option::is_none")]
+  pub(crate) qualified_name: Option<QualifiedName>,
+  #[serde(rename = "k", skip_serializing_if = "Option::is_none")]
+  pub(crate) kind: Option<NodeKind>,
+  #[serde(rename = "a", skip_serializing_if = "Option::is_none")]
+  pub(crate) attributes: Option<Vec<Attribute>>,
+  #[serde(rename = "s", skip_serializing_if = "Option::is_none")]
+  pub(crate) namespaces: Option<Vec<Namespace>>,
+  #[serde(rename = "p", skip_serializing_if = "Option::is_none")]
+  pub(crate) parent: Option<Option<usize>>,
+  #[serde(rename = "c", skip_serializing_if = "Option::is_none")]
+  pub(crate) children: Option<Vec<usize>>,
+  #[serde(rename = "o", skip_serializing_if = "Option::is_none")]
+  pub(crate) own_text: Option<String>,
+  #[serde(rename = "t", skip_serializing_if = "Option::is_none")]
+  pub(crate) text: Option<String>,
+  /// The number of ancestors, so 0 for the root and 1 for the document element.
+  #[serde(rename = "d", skip_serializing_if = "Option::is_none")]
+  pub(crate) depth: Option<usize>,
+  /// The number of element siblings before the node.
+  #[serde(rename = "i", skip_serializing_if = "Option::is_none")]
+  pub(crate) sibling_index: Option<usize>,
+}
+
+/// How frames are encoded once the handshake is done. The handshake itself is always JSON.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Encoding {
+  #[serde(rename = "json")]
+  Json,
+  #[serde(rename = "msgpack")]
+  MessagePack,
+}
+
+/// What kind of failure a `BadRequest` reports, for workers to act on without parsing the reason.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ErrorKind {
+  #[serde(rename = "selector-parse")]
+  SelectorParse,
+  #[serde(rename = "unsupported-check")]
+  UnsupportedCheck,
+  #[serde(rename = "bad-check-argument")]
+  BadCheckArgument,
+  #[serde(rename = "unknown-node")]
+  UnknownNode,
+  #[serde(rename = "serialization")]
+  Serialization,
+  #[serde(rename = "not-batchable")]
+  NotBatchable,
+  #[serde(rename = "malformed-request")]
+  MalformedRequest,
+  #[serde(rename = "payload-too-large")]
+  PayloadTooLarge,
+  #[serde(rename = "protocol-version")]
+  ProtocolVersion,
+  #[serde(rename = "invalid-result")]
+  InvalidResult,
+}
+
+/// Whatever is known about where a request went wrong. Only the fields that apply are sent.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct ErrorDetails {
+  /// Character offset into the selector.
+  #[serde(rename = "o", skip_serializing_if = "Option::is_none")]
+  pub(crate) offset: Option<usize>,
+  /// Character offset of the selector path that failed to parse, when the position of the
+  /// failure itself is not known.
+  #[serde(rename = "p", skip_serializing_if = "Option::is_none")]
+  pub(crate) path_offset: Option<usize>,
+  /// Index of the selector step, counted from 0.
+  #[serde(rename = "s", skip_serializing_if = "Option::is_none")]
+  pub(crate) step: Option<usize>,
+  /// Name of the check function.
+  #[serde(rename = "f", skip_serializing_if = "Option::is_none")]
+  pub(crate) function: Option<String>,
+  #[serde(rename = "n", skip_serializing_if = "Option::is_none")]
+  pub(crate) node_id: Option<usize>,
+  /// Index of the offending write instruction.
+  #[serde(rename = "i", skip_serializing_if = "Option::is_none")]
+  pub(crate) instruction: Option<usize>,
+  /// The largest payload the engine accepts, in bytes.
+  #[serde(rename = "l", skip_serializing_if = "Option::is_none")]
+  pub(crate) limit: Option<usize>,
+  /// The protocol version the engine speaks.
+  #[serde(rename = "v", skip_serializing_if = "Option::is_none")]
+  pub(crate) version: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -24,7 +190,7 @@ pub(crate) struct Attribute {
   pub(crate) value: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct Namespace {
   #[serde(rename = "p")]
   pub(crate) prefix: String,
@@ -42,6 +208,28 @@ pub(crate) struct TransformResult {
   pub(crate) selector: String,
   #[serde(rename = "i")]
   pub(crate) instructions: Vec<WriteInstruction>,
+  /// Where the transform is declared in the manifest's list of transforms.
+  #[serde(rename = "x", default)]
+  pub(crate) index: usize,
+  #[serde(rename = "p", default)]
+  pub(crate) priority: i64,
+}
+
+/// Which output is kept when several transforms produce one for the same node and mode.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RacePolicy {
+  /// The bake fails.
+  #[serde(rename = "fail")]
+  Fail,
+  /// The transform declared first wins.
+  #[serde(rename = "first")]
+  First,
+  /// The transform with the highest priority wins, then the one declared first.
+  #[serde(rename = "priority")]
+  Priority,
+  /// Every output is written, in the order the transforms are declared.
+  #[serde(rename = "compose")]
+  Compose,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -96,12 +284,38 @@ pub(crate) enum WriteInstruction {
 
 #[derive(Deserialize, Debug)]
 pub(crate) enum Request {
+  /// Opens every connection.
+  #[serde(rename = "HI")]
+  Hello {
+    #[serde(rename = "v")]
+    version: u32,
+    #[serde(rename = "c", default)]
+    capabilities: Vec<String>,
+    /// Names of the encodings the worker can speak besides JSON.
+    #[serde(rename = "e", default)]
+    encodings: Vec<String>,
+  },
   #[serde(rename = "S")]
   Selection {
     #[serde(rename = "n")]
     node_id: usize,
     #[serde(rename = "s")]
     selector: String,
+    #[serde(rename = "l", default)]
+    limit: Option<usize>,
+    /// When given, every selected element carries a descriptor with these fields.
+    #[serde(rename = "f", default)]
+    fields: Option<Vec<DescriptorField>>,
+    /// When set, every selected element carries its location in the source.
+    #[serde(rename = "o", default)]
+    locations: bool,
+  },
+  #[serde(rename = "M")]
+  Matches {
+    #[serde(rename = "n")]
+    node_ids: Vec<usize>,
+    #[serde(rename = "s")]
+    selector: String,
   },
   #[serde(rename = "T")]
   Text {
@@ -113,6 +327,57 @@ pub(crate) enum Request {
     #[serde(rename = "n")]
     node_id: usize,
   },
+  #[serde(rename = "L")]
+  Location {
+    #[serde(rename = "n")]
+    node_id: usize,
+  },
+  /// Describes a node. Without a field mask every field is included.
+  #[serde(rename = "D")]
+  Describe {
+    #[serde(rename = "n")]
+    node_id: usize,
+    #[serde(rename = "f", default)]
+    fields: Option<Vec<DescriptorField>>,
+  },
+  /// The markup of a node, or only of its contents when `inner` is set.
+  #[serde(rename = "X")]
+  Serialize {
+    #[serde(rename = "n")]
+    node_id: usize,
+    #[serde(rename = "i", default)]
+    inner: bool,
+  },
+  /// Which of two nodes comes first in the document.
+  #[serde(rename = "O")]
+  Order {
+    #[serde(rename = "a")]
+    first: usize,
+    #[serde(rename = "b")]
+    second: usize,
+  },
+  /// Whether one node is a proper ancestor of another.
+  #[serde(rename = "AN")]
+  Ancestor {
+    #[serde(rename = "a")]
+    ancestor: usize,
+    #[serde(rename = "n")]
+    node_id: usize,
+  },
+  /// The nearest ancestor of a node that matches the selector, not counting the node itself.
+  #[serde(rename = "CL")]
+  Closest {
+    #[serde(rename = "n")]
+    node_id: usize,
+    #[serde(rename = "s")]
+    selector: String,
+  },
+  /// Several queries answered together. Only queries about the document may be batched.
+  #[serde(rename = "Q")]
+  Batch {
+    #[serde(rename = "r")]
+    requests: Vec<Request>,
+  },
   #[serde(rename = "R")]
   PutResults {
     #[serde(rename = "r")]
@@ -129,18 +394,45 @@ pub(crate) enum Request {
   PutError {
     #[serde(rename = "m")]
     message: String,
+    /// The node the failing transform was working on, if known.
+    #[serde(rename = "n", default)]
+    node_id: Option<usize>,
+    /// The kind of the `BadRequest` the transform failed on, if that is how it failed.
+    #[serde(rename = "k", default)]
+    kind: Option<ErrorKind>,
   },
   #[serde(rename = "H")]
   HeartBeat,
+  /// Settings from the manifest. Settings given on the command line take precedence.
+  #[serde(rename = "CF")]
+  Configure {
+    #[serde(rename = "r", default)]
+    race_policy: Option<RacePolicy>,
+  },
 }
 
 #[derive(Serialize, Debug)]
 pub(crate) enum Response {
+  #[serde(rename = "HI")]
+  Hello {
+    #[serde(rename = "v")]
+    version: u32,
+    #[serde(rename = "c")]
+    capabilities: Vec<String>,
+    /// The encoding of every later frame. Only sent in answer to the opening `Hello`.
+    #[serde(rename = "e", skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
+  },
   #[serde(rename = "S")]
   Selection {
     #[serde(rename = "e")]
     elements: Vec<Element>,
   },
+  #[serde(rename = "M")]
+  Matches {
+    #[serde(rename = "m")]
+    matches: Vec<bool>,
+  },
   #[serde(rename = "T")]
   Text {
     #[serde(rename = "t")]
@@ -151,9 +443,53 @@ pub(crate) enum Response {
     #[serde(rename = "a")]
     attributes: Vec<Attribute>,
   },
+  #[serde(rename = "L")]
+  Location {
+    #[serde(rename = "o")]
+    location: Location,
+  },
+  #[serde(rename = "D")]
+  Describe {
+    #[serde(rename = "d")]
+    descriptor: NodeDescriptor,
+  },
+  #[serde(rename = "X")]
+  Serialize {
+    #[serde(rename = "x")]
+    xml: String,
+  },
+  /// -1, 0 or 1 as the first node comes before, is, or comes after the second.
+  #[serde(rename = "O")]
+  Order {
+    #[serde(rename = "o")]
+    order: i8,
+  },
+  #[serde(rename = "AN")]
+  Ancestor {
+    #[serde(rename = "r")]
+    is_ancestor: bool,
+  },
+  #[serde(rename = "CL")]
+  Closest {
+    #[serde(rename = "e")]
+    element: Option<Element>,
+  },
   #[serde(rename = "B")]
   BadRequest {
+    #[serde(rename = "k")]
+    kind: ErrorKind,
     #[serde(rename = "r")]
     reason: String,
+    #[serde(rename = "d")]
+    details: ErrorDetails,
+  },
+  #[serde(rename = "K")]
+  Acknowledged,
+  /// One response per batched request, in request order. Each either succeeds or is a
+  /// `BadRequest` of its own.
+  #[serde(rename = "Q")]
+  Batch {
+    #[serde(rename = "r")]
+    responses: Vec<Response>,
   },
 }
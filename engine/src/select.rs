@@ -1,47 +1,568 @@
+use crate::error::{CheckErrorReason, SelectorError, SelectorResult};
+use crate::index::DocumentIndex;
+use crate::DocumentWrapper;
+use regex::{Regex, RegexBuilder};
 use roxmltree::{Attribute, Node};
 use scandent::{
   ArgValue, AttributeRequirement, AttributeRequirementOperation, Axis, Namespace, Predicate,
-  ScandentResult, Selector, Step,
+  Selector, Step,
 };
 use std::borrow::Cow;
-use std::collections::BTreeSet;
-use std::iter;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::iter::{self, Peekable};
 
 pub(crate) struct ActionableSelector {
   pub original: Selector,
   steps: Vec<ActionableStep>,
+  selects_comments_and_pis: bool,
 }
 impl ActionableSelector {
-  fn from_selector(selector: Selector) -> ActionableSelector {
-    ActionableSelector {
+  pub(crate) fn from_selector(selector: Selector) -> SelectorResult<ActionableSelector> {
+    Ok(ActionableSelector {
       original: selector.clone(),
+      selects_comments_and_pis: selector
+        .steps
+        .last()
+        .map(|step| {
+          step
+            .predicate
+            .checks
+            .iter()
+            .any(|check| check.name == "comment" || check.name == "pi")
+        })
+        .unwrap_or(false),
       steps: selector
         .steps
         .into_iter()
-        .map(ActionableStep::from_step)
-        .collect(),
-    }
+        .enumerate()
+        .map(|(index, step)| ActionableStep::from_step(step, index))
+        .collect::<SelectorResult<Vec<ActionableStep>>>()?,
+    })
   }
   pub(crate) fn from_string<'a, T: Into<Cow<'a, str>>>(
     source: T,
-  ) -> ScandentResult<ActionableSelector> {
+  ) -> SelectorResult<ActionableSelector> {
     let selector = Selector::from_string(source)?;
-    Ok(ActionableSelector::from_selector(selector))
+    ActionableSelector::from_selector(selector)
+  }
+
+  /// Comments and processing instructions are only selected by a path whose last step asks for
+  /// them with a `comment` or `pi` check, so that untyped steps select what they always have.
+  fn yields(&self, node: Node) -> bool {
+    self.selects_comments_and_pis || !(node.is_comment() || node.is_pi())
+  }
+
+  /// Whether resolving this selector from `start` would yield `node`. Works backwards from
+  /// `node`, so only the nodes that could have led to it are visited.
+  pub(crate) fn matches(&self, start: Node, node: Node) -> bool {
+    steps_match(&self.steps, start, node, &mut HashMap::new())
+  }
+}
+
+/// Settled outcomes are remembered by step index and node id, since steps like descendant and
+/// ancestor reach the same origins along many paths and would otherwise revisit them all.
+fn steps_match(
+  steps: &[ActionableStep],
+  start: Node,
+  node: Node,
+  settled: &mut HashMap<(usize, usize), bool>,
+) -> bool {
+  let (step, rest) = match steps.split_last() {
+    Some(split) => split,
+    None => return node == start,
+  };
+  let key = (rest.len(), node.id().get_usize());
+  if let Some(&matched) = settled.get(&key) {
+    return matched;
+  }
+  let matched = (step.condition.filter)(node)
+    && step.origins(node).any(|origin| {
+      (step.condition.positional.is_none()
+        || step.candidates(origin, None).any(|other| other == node))
+        && steps_match(rest, start, origin, settled)
+    });
+  settled.insert(key, matched);
+  matched
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum SetOperation {
+  Union,
+  Intersection,
+  Difference,
+}
+
+impl SetOperation {
+  fn from_keyword(keyword: &str) -> Option<SetOperation> {
+    match keyword {
+      "union" => Some(SetOperation::Union),
+      "intersect" => Some(SetOperation::Intersection),
+      "except" => Some(SetOperation::Difference),
+      _ => None,
+    }
+  }
+}
+
+/// One or more selector paths combined with `union`, `intersect` and `except`. As in XPath,
+/// `intersect` and `except` bind tighter than `union`, and operators of equal precedence
+/// associate to the left.
+pub(crate) enum SelectorExpression {
+  Path(ActionableSelector),
+  Combined(
+    SetOperation,
+    Box<SelectorExpression>,
+    Box<SelectorExpression>,
+  ),
+}
+
+impl SelectorExpression {
+  pub(crate) fn from_string(source: &str) -> SelectorResult<SelectorExpression> {
+    let (operands, operators) = split_expression(source)?;
+    let mut operands = operands.into_iter();
+    let mut operators = operators.into_iter();
+    let mut union_terms = vec![];
+    let path = |operand: &str| {
+      ActionableSelector::from_string(operand)
+        .map(SelectorExpression::Path)
+        .map_err(|err| err.in_path_at(char_offset(source, operand)))
+    };
+    let mut term = path(operands.next().expect("There is always one operand."))?;
+    for operand in operands {
+      let operation = operators.next().expect("Operators sit between operands.");
+      let path = path(operand)?;
+      if operation == SetOperation::Union {
+        union_terms.push(term);
+        term = path;
+      } else {
+        term = SelectorExpression::Combined(operation, Box::new(term), Box::new(path));
+      }
+    }
+    Ok(union_terms.into_iter().rev().fold(term, |right, left| {
+      SelectorExpression::Combined(SetOperation::Union, Box::new(left), Box::new(right))
+    }))
+  }
+}
+
+impl SelectorExpression {
+  pub(crate) fn matches(&self, start: Node, node: Node) -> bool {
+    match self {
+      SelectorExpression::Path(selector) => selector.yields(node) && selector.matches(start, node),
+      SelectorExpression::Combined(operation, left, right) => match operation {
+        SetOperation::Union => left.matches(start, node) || right.matches(start, node),
+        SetOperation::Intersection => left.matches(start, node) && right.matches(start, node),
+        SetOperation::Difference => left.matches(start, node) && !right.matches(start, node),
+      },
+    }
+  }
+}
+
+/// Splits an expression into its selector paths and the operators between them. Keywords only
+/// count as operators when they stand alone outside of brackets, parentheses and quotes.
+fn split_expression(source: &str) -> SelectorResult<(Vec<&str>, Vec<SetOperation>)> {
+  let mut operands = vec![];
+  let mut operators = vec![];
+  let mut depth = 0usize;
+  let mut quote: Option<char> = None;
+  let mut escaped = false;
+  let mut operand_start = 0;
+  let mut word_start: Option<usize> = None;
+  let mut chars = source.char_indices().peekable();
+  while let Some((offset, character)) = chars.next() {
+    if let Some(open_quote) = quote {
+      if escaped {
+        escaped = false;
+      } else if character == '\\' {
+        escaped = true;
+      } else if character == open_quote {
+        quote = None;
+      }
+      continue;
+    }
+    match character {
+      '"' | '\'' => quote = Some(character),
+      '[' | '(' => depth += 1,
+      ']' | ')' => depth = depth.saturating_sub(1),
+      _ => {}
+    }
+    if depth > 0 || !character.is_alphabetic() {
+      word_start = None;
+      continue;
+    }
+    let at_word_start = offset == 0 || source[..offset].ends_with(char::is_whitespace);
+    if at_word_start {
+      word_start = Some(offset);
+    }
+    let at_word_end = chars
+      .peek()
+      .map(|(_, next)| next.is_whitespace())
+      .unwrap_or(true);
+    if let (Some(start), true) = (word_start, at_word_end) {
+      if let Some(operation) = SetOperation::from_keyword(&source[start..offset + 1]) {
+        operands.push(operand(source, operand_start, start)?);
+        operators.push(operation);
+        operand_start = offset + 1;
+      }
+    }
   }
+  operands.push(operand(source, operand_start, source.len())?);
+  Ok((operands, operators))
+}
+
+fn operand(source: &str, start: usize, end: usize) -> SelectorResult<&str> {
+  let operand = source[start..end].trim();
+  if operand.is_empty() {
+    Err(SelectorError::MissingOperand {
+      offset: source[..start].chars().count(),
+    })
+  } else {
+    Ok(operand)
+  }
+}
 
-  pub(crate) fn _inverse(&self) -> ActionableSelector {
-    ActionableSelector::from_selector(self.original.inverse())
+/// The offset in characters of `part`, which must be a slice of `source`.
+fn char_offset(source: &str, part: &str) -> usize {
+  let start = part.as_ptr() as usize - source.as_ptr() as usize;
+  source[..start].chars().count()
+}
+
+pub(crate) fn resolve_expression<'s, 'a: 's, 'b: 'a>(
+  start: Node<'a, 'b>,
+  expression: &'s SelectorExpression,
+  index: Option<&'s DocumentIndex>,
+) -> Selection<'s, 'a, 'b> {
+  match expression {
+    SelectorExpression::Path(selector) => {
+      Box::new(resolve_selector(start, selector, index).filter(move |node| selector.yields(*node)))
+    }
+    SelectorExpression::Combined(operation, left, right) => Box::new(SetMerge {
+      operation: *operation,
+      left: resolve_expression(start, left, index).peekable(),
+      right: resolve_expression(start, right, index).peekable(),
+    }),
+  }
+}
+
+/// Combines two document-ordered selections without collecting either of them.
+struct SetMerge<'s, 'a, 'b> {
+  operation: SetOperation,
+  left: Peekable<Selection<'s, 'a, 'b>>,
+  right: Peekable<Selection<'s, 'a, 'b>>,
+}
+
+impl<'s, 'a: 's, 'b: 'a> Iterator for SetMerge<'s, 'a, 'b> {
+  type Item = Node<'a, 'b>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      let left = self.left.peek().map(|node| node.id().get_usize());
+      let right = self.right.peek().map(|node| node.id().get_usize());
+      let (take_left, take_right) = match (left, right) {
+        (None, None) => return None,
+        (Some(_), None) => (true, false),
+        (None, Some(_)) => (false, true),
+        (Some(left), Some(right)) => (left <= right, right <= left),
+      };
+      let left = if take_left { self.left.next() } else { None };
+      let right = if take_right { self.right.next() } else { None };
+      let keep = match self.operation {
+        SetOperation::Union => left.or(right),
+        SetOperation::Intersection => left.and(right),
+        SetOperation::Difference => {
+          if right.is_some() {
+            None
+          } else {
+            left
+          }
+        }
+      };
+      if keep.is_some() {
+        return keep;
+      }
+      if self.operation != SetOperation::Union {
+        // Nothing more can be produced once the side every result depends on runs dry.
+        let exhausted = match self.operation {
+          SetOperation::Intersection => self.left.peek().is_none() || self.right.peek().is_none(),
+          _ => self.left.peek().is_none(),
+        };
+        if exhausted {
+          return None;
+        }
+      }
+    }
   }
 }
 
+type Condition = Box<dyn Fn(Node) -> bool + Sync + Send>;
+type PositionalCondition = Box<dyn Fn(Node, Position) -> bool + Sync + Send>;
+
+/// Where a candidate sits among the nodes a step's axis yields from one origin that pass the
+/// step's position-independent requirements. `index` is 1-based and counts in axis order, so
+/// on reverse axes the nearest node comes first. `size` is only computed when a check needs it.
+#[derive(Copy, Clone)]
+struct Position {
+  index: usize,
+  size: Option<usize>,
+}
+
+struct PredicateCondition {
+  filter: Condition,
+  positional: Option<PositionalCondition>,
+  needs_size: bool,
+}
+
 struct ActionableStep {
   axis: Axis,
-  condition: Box<dyn Fn(Node) -> bool + Sync + Send>,
+  condition: PredicateCondition,
+  hint: Option<IndexHint>,
+}
+
+/// A requirement of a step's predicate that the document index can answer directly. The
+/// indexed nodes are a superset of the matches; the step's condition still filters them.
+enum IndexHint {
+  Name(String),
+  Id(String),
+  Class(String),
+}
+
+impl IndexHint {
+  fn from_predicate(predicate: &Predicate) -> Option<IndexHint> {
+    // The index holds ids and classes from every namespace, which is what a requirement naming
+    // no namespace matches.
+    let unqualified = |attr_req: &&AttributeRequirement, localname| {
+      attr_req.name.namespace.is_none() && attr_req.name.localname.as_deref() == Some(localname)
+    };
+    for attr_req in predicate
+      .attributes
+      .iter()
+      .filter(|attr_req| unqualified(attr_req, "id"))
+    {
+      if let AttributeRequirementOperation::Equals(value) = &attr_req.op {
+        return Some(IndexHint::Id(value.clone()));
+      }
+    }
+    for attr_req in predicate
+      .attributes
+      .iter()
+      .filter(|attr_req| unqualified(attr_req, "class"))
+    {
+      match &attr_req.op {
+        AttributeRequirementOperation::Contains(value) => {
+          return Some(IndexHint::Class(value.clone()))
+        }
+        AttributeRequirementOperation::Equals(value)
+          if !value.is_empty() && !value.contains(char::is_whitespace) =>
+        {
+          return Some(IndexHint::Class(value.clone()))
+        }
+        _ => {}
+      }
+    }
+    predicate.name.localname.clone().map(IndexHint::Name)
+  }
+
+  fn indexed<'i>(&self, index: &'i DocumentIndex) -> &'i [usize] {
+    match self {
+      IndexHint::Name(local_name) => index.named(local_name),
+      IndexHint::Id(id) => index.with_id(id),
+      IndexHint::Class(class) => index.with_class(class),
+    }
+  }
+}
+
+enum MatchOperation {
+  Equals,
+  Contains,
+  StartsWith,
+  EndsWith,
+  Matches(Regex),
+}
+
+/// Compares strings found in the document against a value given in the selector. Flags are
+/// passed as an optional trailing argument; `i` makes the comparison case-insensitive and `n`
+/// trims and collapses runs of whitespace before comparing.
+struct StringMatcher {
+  operation: MatchOperation,
+  value: String,
+  case_insensitive: bool,
+  normalize_whitespace: bool,
+}
+
+fn normalize_whitespace(value: &str) -> String {
+  value.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+impl StringMatcher {
+  fn from_arguments(
+    operation_name: &str,
+    args: &[ArgValue],
+    index: usize,
+  ) -> Result<StringMatcher, CheckErrorReason> {
+    let value = string_argument(args, index)?;
+    let flags = match args.get(index + 1) {
+      Some(_) => string_argument(args, index + 1)?,
+      None => "",
+    };
+    if flags.chars().any(|flag| flag != 'i' && flag != 'n') {
+      return Err(CheckErrorReason::BadArgument {
+        index: index + 1,
+        expected: "a combination of the flags `i` and `n`",
+      });
+    }
+    let case_insensitive = flags.contains('i');
+    let normalize = flags.contains('n');
+    let value = if normalize {
+      Cow::Owned(normalize_whitespace(value))
+    } else {
+      Cow::Borrowed(value)
+    };
+    let operation = match operation_name {
+      "equals" => MatchOperation::Equals,
+      "contains" => MatchOperation::Contains,
+      "starts" => MatchOperation::StartsWith,
+      "ends" => MatchOperation::EndsWith,
+      "matches" => MatchOperation::Matches(
+        RegexBuilder::new(&value)
+          .case_insensitive(case_insensitive)
+          .build()
+          .map_err(|_| CheckErrorReason::BadArgument {
+            index,
+            expected: "a valid regular expression",
+          })?,
+      ),
+      _ => return Err(CheckErrorReason::UnknownFunction),
+    };
+    Ok(StringMatcher {
+      operation,
+      value: if case_insensitive {
+        value.to_lowercase()
+      } else {
+        value.into_owned()
+      },
+      case_insensitive,
+      normalize_whitespace: normalize,
+    })
+  }
+
+  fn test(&self, candidate: &str) -> bool {
+    let candidate = if self.normalize_whitespace {
+      Cow::Owned(normalize_whitespace(candidate))
+    } else {
+      Cow::Borrowed(candidate)
+    };
+    if let MatchOperation::Matches(regex) = &self.operation {
+      return regex.is_match(&candidate);
+    }
+    let candidate = if self.case_insensitive {
+      Cow::Owned(candidate.to_lowercase())
+    } else {
+      candidate
+    };
+    let value = self.value.as_str();
+    match self.operation {
+      MatchOperation::Equals => candidate == value,
+      MatchOperation::Contains => candidate.contains(value),
+      MatchOperation::StartsWith => candidate.starts_with(value),
+      MatchOperation::EndsWith => candidate.ends_with(value),
+      MatchOperation::Matches(_) => unreachable!("Handled above."),
+    }
+  }
+}
+
+/// Splits an attribute name given as `local` or `{uri}local`. A bare local name matches the
+/// attribute in any namespace.
+fn attribute_name_argument(name: &str) -> (Option<String>, String) {
+  if name.starts_with('{') {
+    if let Some(end) = name.find('}') {
+      return (Some(name[1..end].to_owned()), name[end + 1..].to_owned());
+    }
+  }
+  (None, name.to_owned())
+}
+
+/// The text directly inside a node, not counting text nested in child elements.
+pub(crate) fn own_text(node: Node) -> String {
+  if node.is_text() {
+    return node.text().unwrap_or("").to_owned();
+  }
+  node
+    .children()
+    .filter(|child| child.is_text())
+    .filter_map(|child| child.text())
+    .collect()
+}
+
+enum CheckCondition {
+  Filter(Condition),
+  Positional(PositionalCondition, bool),
+}
+
+fn expect_arguments(args: &[ArgValue], expected: usize) -> Result<(), CheckErrorReason> {
+  expect_argument_range(args, expected, expected)
+}
+
+fn expect_argument_range(
+  args: &[ArgValue],
+  min: usize,
+  max: usize,
+) -> Result<(), CheckErrorReason> {
+  if args.len() >= min && args.len() <= max {
+    Ok(())
+  } else {
+    Err(CheckErrorReason::ArgumentCount {
+      min,
+      max,
+      found: args.len(),
+    })
+  }
+}
+
+fn string_argument(args: &[ArgValue], index: usize) -> Result<&str, CheckErrorReason> {
+  match args.get(index) {
+    Some(ArgValue::StringValue(value)) => Ok(value),
+    _ => Err(CheckErrorReason::BadArgument {
+      index,
+      expected: "a string",
+    }),
+  }
+}
+
+fn integer_argument(args: &[ArgValue], index: usize) -> Result<usize, CheckErrorReason> {
+  let bad_argument = CheckErrorReason::BadArgument {
+    index,
+    expected: "a positive integer",
+  };
+  match args.get(index) {
+    Some(ArgValue::StringValue(value)) => match value.trim().parse() {
+      Ok(number) if number > 0 => Ok(number),
+      _ => Err(bad_argument),
+    },
+    _ => Err(bad_argument),
+  }
+}
+
+fn predicate_argument(args: &[ArgValue], index: usize) -> Result<&Predicate, CheckErrorReason> {
+  match args.get(index) {
+    Some(ArgValue::PredicateValue(predicate)) => Ok(predicate),
+    _ => Err(CheckErrorReason::BadArgument {
+      index,
+      expected: "a predicate",
+    }),
+  }
+}
+
+fn is_same_element(node: Node, other: Node) -> bool {
+  other.is_element() && other.tag_name() == node.tag_name()
 }
 
 impl ActionableStep {
-  fn condition_from_predicate(predicate: Predicate) -> Box<dyn Fn(Node) -> bool + Sync + Send> {
-    let mut conditions: Vec<Box<dyn Fn(Node) -> bool + Sync + Send>> = vec![];
+  fn condition_from_predicate(
+    predicate: Predicate,
+    step: usize,
+  ) -> SelectorResult<PredicateCondition> {
+    let mut conditions: Vec<Condition> = vec![];
+    let mut positional_conditions: Vec<PositionalCondition> = vec![];
+    let mut needs_size = false;
 
     let name_requirement = predicate.name;
     if let Some(localname) = name_requirement.localname {
@@ -124,67 +645,252 @@ impl ActionableStep {
 
     let path_requirements = predicate.paths;
     for path_req in path_requirements {
-      let actionable = ActionableSelector::from_selector(path_req);
+      let actionable =
+        ActionableSelector::from_selector(path_req).map_err(|err| err.in_step(step))?;
       conditions.push(Box::from(move |node: Node| {
-        !resolve_selector(node, &actionable).is_empty()
+        resolve_selector(node, &actionable, None).next().is_some()
       }));
     }
 
     let check_functions_requirements = predicate.checks;
     for check_function_req in check_functions_requirements {
-      match check_function_req.name.as_ref() {
-        "element" => {
-          conditions.push(Box::from(move |node: Node| node.is_element()));
+      let check_condition = ActionableStep::condition_from_check(
+        &check_function_req.name,
+        &check_function_req.args,
+        step,
+      )?;
+      match check_condition {
+        CheckCondition::Filter(condition) => conditions.push(condition),
+        CheckCondition::Positional(condition, condition_needs_size) => {
+          needs_size |= condition_needs_size;
+          positional_conditions.push(condition);
         }
-        "text" => {
-          conditions.push(Box::from(move |node: Node| node.is_text()));
-        }
-        "not" => match &check_function_req.args[0] {
-          ArgValue::PredicateValue(predicate_arg) => {
-            let predicate_condition =
-              ActionableStep::condition_from_predicate(predicate_arg.clone());
-            conditions.push(Box::from(move |node: Node| !predicate_condition(node)));
-          }
-          _ => panic!("Bad argument type"),
-        },
-        _ => {
-          conditions.push(Box::from(move |_node: Node| unimplemented!()));
-        }
-      };
+      }
     }
 
-    Box::from(move |node: Node| conditions.iter().all(|condition| condition(node)))
+    Ok(PredicateCondition {
+      filter: Box::from(move |node: Node| conditions.iter().all(|condition| condition(node))),
+      positional: if positional_conditions.is_empty() {
+        None
+      } else {
+        Some(Box::from(move |node: Node, position: Position| {
+          positional_conditions
+            .iter()
+            .all(|condition| condition(node, position))
+        }))
+      },
+      needs_size,
+    })
+  }
+
+  fn condition_from_check(
+    name: &str,
+    args: &[ArgValue],
+    step: usize,
+  ) -> SelectorResult<CheckCondition> {
+    let fail = |reason| SelectorError::CheckError {
+      step,
+      function: name.to_owned(),
+      reason,
+    };
+    let condition = match name {
+      "element" => {
+        expect_arguments(args, 0).map_err(fail)?;
+        CheckCondition::Filter(Box::from(move |node: Node| node.is_element()))
+      }
+      "text" => {
+        expect_arguments(args, 0).map_err(fail)?;
+        CheckCondition::Filter(Box::from(move |node: Node| node.is_text()))
+      }
+      "comment" => {
+        expect_arguments(args, 0).map_err(fail)?;
+        CheckCondition::Filter(Box::from(move |node: Node| node.is_comment()))
+      }
+      "pi" => {
+        expect_argument_range(args, 0, 1).map_err(fail)?;
+        let target = match args.first() {
+          Some(_) => Some(string_argument(args, 0).map_err(fail)?.to_owned()),
+          None => None,
+        };
+        CheckCondition::Filter(Box::from(move |node: Node| match node.pi() {
+          Some(pi) => target
+            .as_ref()
+            .map(|target| pi.target == target)
+            .unwrap_or(true),
+          None => false,
+        }))
+      }
+      "not" => {
+        expect_arguments(args, 1).map_err(fail)?;
+        let predicate_arg = predicate_argument(args, 0).map_err(fail)?;
+        let PredicateCondition {
+          filter,
+          positional,
+          needs_size,
+        } = ActionableStep::condition_from_predicate(predicate_arg.clone(), step)?;
+        match positional {
+          None => CheckCondition::Filter(Box::from(move |node: Node| !filter(node))),
+          Some(positional) => CheckCondition::Positional(
+            Box::from(move |node: Node, position: Position| {
+              !(filter(node) && positional(node, position))
+            }),
+            needs_size,
+          ),
+        }
+      }
+      "first" => {
+        expect_arguments(args, 0).map_err(fail)?;
+        CheckCondition::Positional(
+          Box::from(|_: Node, position: Position| position.index == 1),
+          false,
+        )
+      }
+      "last" => {
+        expect_arguments(args, 0).map_err(fail)?;
+        CheckCondition::Positional(
+          Box::from(|_: Node, position: Position| Some(position.index) == position.size),
+          true,
+        )
+      }
+      "nth" => {
+        expect_arguments(args, 1).map_err(fail)?;
+        let n = integer_argument(args, 0).map_err(fail)?;
+        CheckCondition::Positional(
+          Box::from(move |_: Node, position: Position| position.index == n),
+          false,
+        )
+      }
+      "nth-last" => {
+        expect_arguments(args, 1).map_err(fail)?;
+        let n = integer_argument(args, 0).map_err(fail)?;
+        CheckCondition::Positional(
+          Box::from(move |_: Node, position: Position| {
+            position.size.map(|size| size + 1 - position.index) == Some(n)
+          }),
+          true,
+        )
+      }
+      "nth-of-type" => {
+        expect_arguments(args, 1).map_err(fail)?;
+        let n = integer_argument(args, 0).map_err(fail)?;
+        CheckCondition::Filter(Box::from(move |node: Node| {
+          node.is_element()
+            && node
+              .prev_siblings()
+              .filter(|sibling| is_same_element(node, *sibling))
+              .count()
+              == n
+        }))
+      }
+      "empty" => {
+        expect_arguments(args, 0).map_err(fail)?;
+        CheckCondition::Filter(Box::from(|node: Node| {
+          node.is_element()
+            && !node
+              .children()
+              .any(|child| child.is_element() || child.is_text())
+        }))
+      }
+      "only-child" => {
+        expect_arguments(args, 0).map_err(fail)?;
+        CheckCondition::Filter(Box::from(|node: Node| {
+          node.is_element()
+            && node.parent().is_some()
+            && !node
+              .prev_siblings()
+              .skip(1)
+              .any(|sibling| sibling.is_element())
+            && !node
+              .next_siblings()
+              .skip(1)
+              .any(|sibling| sibling.is_element())
+        }))
+      }
+      attr_check if attr_check.starts_with("attr-") => {
+        expect_argument_range(args, 2, 3).map_err(fail)?;
+        let (uri, localname) = attribute_name_argument(string_argument(args, 0).map_err(fail)?);
+        let matcher =
+          StringMatcher::from_arguments(&attr_check["attr-".len()..], args, 1).map_err(fail)?;
+        CheckCondition::Filter(Box::from(move |node: Node| {
+          node.attributes().iter().any(|attr| {
+            attr.name() == localname
+              && uri
+                .as_ref()
+                .map(|uri| attr.namespace().unwrap_or("") == uri)
+                .unwrap_or(true)
+              && matcher.test(attr.value())
+          })
+        }))
+      }
+      text_check if text_check.starts_with("text-") => {
+        expect_argument_range(args, 1, 2).map_err(fail)?;
+        let matcher =
+          StringMatcher::from_arguments(&text_check["text-".len()..], args, 0).map_err(fail)?;
+        CheckCondition::Filter(Box::from(move |node: Node| {
+          matcher.test(&DocumentWrapper::deep_text(node))
+        }))
+      }
+      own_text_check if own_text_check.starts_with("own-text-") => {
+        expect_argument_range(args, 1, 2).map_err(fail)?;
+        let matcher = StringMatcher::from_arguments(&own_text_check["own-text-".len()..], args, 0)
+          .map_err(fail)?;
+        CheckCondition::Filter(Box::from(move |node: Node| matcher.test(&own_text(node))))
+      }
+      _ => return Err(fail(CheckErrorReason::UnknownFunction)),
+    };
+    Ok(condition)
   }
 
-  fn from_step(step: Step) -> ActionableStep {
-    ActionableStep {
+  fn from_step(step: Step, index: usize) -> SelectorResult<ActionableStep> {
+    Ok(ActionableStep {
       axis: step.axis,
-      condition: ActionableStep::condition_from_predicate(step.predicate),
+      hint: IndexHint::from_predicate(&step.predicate),
+      condition: ActionableStep::condition_from_predicate(step.predicate, index)?,
+    })
+  }
+
+  /// Every node from which this step's axis reaches `node`.
+  fn origins<'a, 'b: 'a>(&self, node: Node<'a, 'b>) -> Box<dyn Iterator<Item = Node<'a, 'b>> + 'a> {
+    match self.axis {
+      Axis::Ancestor => Box::from(node.descendants()),
+      Axis::Parent => Box::from(node.children()),
+      Axis::Descendant => Box::from(node.ancestors()),
+      Axis::Child => Box::from(node.parent().into_iter()),
+      Axis::Next => Box::from(node.prev_sibling().into_iter()),
+      Axis::Previous => Box::from(node.next_sibling().into_iter()),
+      Axis::FollowingSibling => Box::from(node.prev_siblings()),
+      Axis::PrecedingSibling => Box::from(node.next_siblings()),
+      Axis::Current => Box::from(iter::once(node)),
     }
   }
-}
 
-// TODO: needs to return iterator
-pub(crate) fn resolve_selector<'a, 'b: 'a>(
-  start: Node<'a, 'b>,
-  selector: &ActionableSelector,
-) -> BTreeSet<Node<'a, 'b>> {
-  let mut set: BTreeSet<Node> = BTreeSet::new();
-  set.insert(start);
-  selector.steps.iter().fold(set, step_result)
-}
-
-// TODO: needs to return iterator
-fn step_result<'a, 'b: 'a>(
-  start: BTreeSet<Node<'a, 'b>>,
-  step: &ActionableStep,
-) -> BTreeSet<Node<'a, 'b>> {
-  let mut next_set: BTreeSet<Node> = BTreeSet::new();
-  start.into_iter().for_each(|node| {
-    let axis_iter: Box<dyn Iterator<Item = Node>> = match step.axis {
+  /// The nodes this step selects from a single origin, in axis order.
+  fn candidates<'s, 'a: 's, 'b: 'a>(
+    &'s self,
+    node: Node<'a, 'b>,
+    index: Option<&'s DocumentIndex>,
+  ) -> Selection<'s, 'a, 'b> {
+    let axis_iter: Box<dyn Iterator<Item = Node>> = match self.axis {
       Axis::Ancestor => Box::from(node.ancestors()),
       Axis::Parent => Box::from(node.parent().into_iter()),
-      Axis::Descendant => Box::from(node.descendants()),
+      Axis::Descendant => match (index, &self.hint) {
+        (Some(index), Some(hint)) => {
+          let indexed = hint.indexed(index);
+          let first = node.id().get_usize();
+          let last = index.subtree_end(node);
+          let start = match indexed.binary_search(&first) {
+            Ok(position) | Err(position) => position,
+          };
+          let document = node.document();
+          Box::from(
+            indexed[start..]
+              .iter()
+              .take_while(move |&&id| id <= last)
+              .map(move |&id| document.get_node(id.into()).expect("Indexed nodes exist.")),
+          )
+        }
+        _ => Box::from(node.descendants()),
+      },
       Axis::Child => Box::from(node.children()),
       Axis::Next => Box::from(node.next_sibling().into_iter()),
       Axis::Previous => Box::from(node.prev_sibling().into_iter()),
@@ -192,11 +898,644 @@ fn step_result<'a, 'b: 'a>(
       Axis::PrecedingSibling => Box::from(node.prev_siblings()),
       Axis::Current => Box::from(iter::once(node)),
     };
-    axis_iter
-      .filter(|node| (step.condition)(*node))
-      .for_each(|node| {
-        next_set.insert(node);
-      });
-  });
-  next_set
+    let filtered = axis_iter.filter(move |node| (self.condition.filter)(*node));
+    let positional = match &self.condition.positional {
+      Some(positional) => positional,
+      None => return Box::new(filtered),
+    };
+    if self.condition.needs_size {
+      let nodes: Vec<Node> = filtered.collect();
+      let size = Some(nodes.len());
+      Box::new(
+        nodes
+          .into_iter()
+          .enumerate()
+          .filter(move |(index, node)| {
+            positional(
+              *node,
+              Position {
+                index: index + 1,
+                size,
+              },
+            )
+          })
+          .map(|(_, node)| node),
+      )
+    } else {
+      Box::new(
+        filtered
+          .enumerate()
+          .filter(move |(index, node)| {
+            positional(
+              *node,
+              Position {
+                index: index + 1,
+                size: None,
+              },
+            )
+          })
+          .map(|(_, node)| node),
+      )
+    }
+  }
+}
+
+/// A lazily evaluated selection. Nodes are yielded in document order without duplicates, so
+/// consumers that only need the first few matches never pay for the rest.
+pub(crate) type Selection<'s, 'a, 'b> = Box<dyn Iterator<Item = Node<'a, 'b>> + 's>;
+
+/// Resolves `selector` from `start`. The document index is optional because it is only
+/// worth consulting for top-level selections, not for path predicates tested per candidate.
+pub(crate) fn resolve_selector<'s, 'a: 's, 'b: 'a>(
+  start: Node<'a, 'b>,
+  selector: &'s ActionableSelector,
+  index: Option<&'s DocumentIndex>,
+) -> Selection<'s, 'a, 'b> {
+  selector
+    .steps
+    .iter()
+    .fold(Box::new(iter::once(start)), |origins, step| {
+      step_result(origins, step, index)
+    })
+}
+
+fn step_result<'s, 'a: 's, 'b: 'a>(
+  origins: Selection<'s, 'a, 'b>,
+  step: &'s ActionableStep,
+  index: Option<&'s DocumentIndex>,
+) -> Selection<'s, 'a, 'b> {
+  match step.axis {
+    Axis::Descendant | Axis::Child | Axis::Next | Axis::FollowingSibling | Axis::Current => {
+      Box::new(ForwardMerge {
+        step,
+        index,
+        origins: origins.peekable(),
+        heads: BinaryHeap::new(),
+        last: None,
+        covered_until: None,
+      })
+    }
+    // Reverse axes can produce nodes before any origin seen so far, so there is nothing to gain
+    // from streaming here. These are rarely used on large selections anyway.
+    Axis::Ancestor | Axis::Parent | Axis::Previous | Axis::PrecedingSibling => {
+      let mut nodes: Vec<Node> = origins
+        .flat_map(|node| step.candidates(node, index))
+        .collect();
+      nodes.sort_by_key(|node| node.id().get_usize());
+      nodes.dedup_by_key(|node| node.id().get_usize());
+      Box::new(nodes.into_iter())
+    }
+  }
+}
+
+/// The head of one origin's candidate stream, ordered so that `BinaryHeap` pops the candidate
+/// that comes first in the document.
+struct Head<'s, 'a, 'b> {
+  node: Node<'a, 'b>,
+  rest: Selection<'s, 'a, 'b>,
+}
+
+impl Head<'_, '_, '_> {
+  fn position(&self) -> usize {
+    self.node.id().get_usize()
+  }
+}
+
+impl PartialEq for Head<'_, '_, '_> {
+  fn eq(&self, other: &Self) -> bool {
+    self.position() == other.position()
+  }
+}
+
+impl Eq for Head<'_, '_, '_> {}
+
+impl PartialOrd for Head<'_, '_, '_> {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Head<'_, '_, '_> {
+  fn cmp(&self, other: &Self) -> Ordering {
+    other.position().cmp(&self.position())
+  }
+}
+
+/// Merges the candidates of every origin for an axis that only walks forward in the document.
+/// Every candidate of an origin comes at or after the origin itself, so origins only have to be
+/// pulled in once the smallest pending candidate is past them.
+struct ForwardMerge<'s, 'a, 'b> {
+  step: &'s ActionableStep,
+  index: Option<&'s DocumentIndex>,
+  origins: Peekable<Selection<'s, 'a, 'b>>,
+  heads: BinaryHeap<Head<'s, 'a, 'b>>,
+  last: Option<usize>,
+  covered_until: Option<usize>,
+}
+
+impl<'s, 'a: 's, 'b: 'a> Iterator for ForwardMerge<'s, 'a, 'b> {
+  type Item = Node<'a, 'b>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      while let Some(origin) = self.origins.peek() {
+        let origin_position = origin.id().get_usize();
+        if let Some(head) = self.heads.peek() {
+          if origin_position > head.position() {
+            break;
+          }
+        }
+        let origin = *origin;
+        self.origins.next();
+        // An origin nested inside an earlier one adds nothing on the descendant axis, unless
+        // positions are counted per origin.
+        if let (Axis::Descendant, Some(index), None) =
+          (&self.step.axis, self.index, &self.step.condition.positional)
+        {
+          if self.covered_until.map(|end| origin_position <= end) == Some(true) {
+            continue;
+          }
+          self.covered_until = Some(index.subtree_end(origin));
+        }
+        let mut candidates = self.step.candidates(origin, self.index);
+        if let Some(node) = candidates.next() {
+          self.heads.push(Head {
+            node,
+            rest: candidates,
+          });
+        }
+      }
+
+      let Head { node, mut rest } = self.heads.pop()?;
+      if let Some(next) = rest.next() {
+        self.heads.push(Head { node: next, rest });
+      }
+      let position = node.id().get_usize();
+      if self.last == Some(position) {
+        continue;
+      }
+      self.last = Some(position);
+      return Some(node);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use roxmltree::Document;
+  use scandent::{CheckFunction, NameRequirement};
+
+  fn named(localname: &str) -> Predicate {
+    Predicate {
+      name: NameRequirement {
+        localname: Some(localname.to_owned()),
+        namespace: None,
+      },
+      attributes: vec![],
+      paths: vec![],
+      checks: vec![],
+    }
+  }
+
+  fn any() -> Predicate {
+    Predicate {
+      name: NameRequirement {
+        localname: None,
+        namespace: None,
+      },
+      ..named("")
+    }
+  }
+
+  fn checked(mut predicate: Predicate, function: &str, args: Vec<ArgValue>) -> Predicate {
+    predicate.checks.push(CheckFunction {
+      name: function.to_owned(),
+      args,
+    });
+    predicate
+  }
+
+  fn number(value: &str) -> ArgValue {
+    ArgValue::StringValue(value.to_owned())
+  }
+
+  fn compile(steps: Vec<(Axis, Predicate)>) -> ActionableSelector {
+    let steps = steps
+      .into_iter()
+      .map(|(axis, predicate)| Step { axis, predicate })
+      .collect();
+    ActionableSelector::from_selector(Selector { steps }).unwrap()
+  }
+
+  /// The text of every selected element, or its name when it has no text.
+  fn describe<'a, 'b: 'a>(selection: Selection<'_, 'a, 'b>) -> Vec<String> {
+    selection
+      .map(|node| {
+        node
+          .text()
+          .unwrap_or_else(|| node.tag_name().name())
+          .to_owned()
+      })
+      .collect()
+  }
+
+  fn select(xml: &str, steps: Vec<(Axis, Predicate)>) -> Vec<String> {
+    let document = Document::parse(xml).unwrap();
+    let selector = compile(steps);
+    describe(resolve_selector(document.root(), &selector, None))
+  }
+
+  const LIST: &str = "<r><a><b>1</b><c/><b>2</b><b>3</b></a><a><b>4</b></a></r>";
+
+  fn child_b(function: &str, args: Vec<ArgValue>) -> Vec<(Axis, Predicate)> {
+    vec![
+      (Axis::Descendant, named("a")),
+      (Axis::Child, checked(named("b"), function, args)),
+    ]
+  }
+
+  #[test]
+  fn positions_count_within_each_parent() {
+    assert_eq!(select(LIST, child_b("first", vec![])), ["1", "4"]);
+    assert_eq!(select(LIST, child_b("last", vec![])), ["3", "4"]);
+    assert_eq!(select(LIST, child_b("only-child", vec![])), ["4"]);
+  }
+
+  #[test]
+  fn nth_counts_every_candidate_of_the_step() {
+    assert_eq!(select(LIST, child_b("nth", vec![number("2")])), ["2"]);
+    assert_eq!(select(LIST, child_b("nth-last", vec![number("2")])), ["2"]);
+    assert_eq!(
+      select(LIST, child_b("nth", vec![number("5")])),
+      [] as [&str; 0]
+    );
+  }
+
+  #[test]
+  fn nth_of_type_counts_only_same_named_siblings() {
+    assert_eq!(
+      select(LIST, child_b("nth-of-type", vec![number("3")])),
+      ["3"]
+    );
+    assert_eq!(
+      select(LIST, child_b("nth-of-type", vec![number("1")])),
+      ["1", "4"]
+    );
+  }
+
+  #[test]
+  fn positional_checks_can_be_negated() {
+    let not_first = ArgValue::PredicateValue(checked(any(), "first", vec![]));
+    assert_eq!(select(LIST, child_b("not", vec![not_first])), ["2", "3"]);
+  }
+
+  #[test]
+  fn ancestor_positions_count_nearest_first() {
+    let xml = "<r><a><c/></a></r>";
+    let nth_ancestor = |n| {
+      vec![
+        (Axis::Descendant, named("c")),
+        (Axis::Ancestor, checked(any(), "nth", vec![number(n)])),
+      ]
+    };
+    // The ancestor axis starts at the node itself.
+    assert_eq!(select(xml, nth_ancestor("1")), ["c"]);
+    assert_eq!(select(xml, nth_ancestor("3")), ["r"]);
+  }
+
+  #[test]
+  fn empty_ignores_position() {
+    let steps = vec![(Axis::Descendant, checked(any(), "empty", vec![]))];
+    assert_eq!(select(LIST, steps), ["c"]);
+  }
+
+  #[test]
+  fn expressions_split_on_standalone_keywords() {
+    let (operands, operators) = split_expression("//a union //b except //c").unwrap();
+    assert_eq!(operands, ["//a", "//b", "//c"]);
+    assert_eq!(operators, [SetOperation::Union, SetOperation::Difference]);
+    let (operands, operators) =
+      split_expression("//a[title='b except c'] intersect //union[x=(y union z)]").unwrap();
+    assert_eq!(
+      operands,
+      ["//a[title='b except c']", "//union[x=(y union z)]"]
+    );
+    assert_eq!(operators, [SetOperation::Intersection]);
+  }
+
+  #[test]
+  fn missing_operands_report_their_offset() {
+    match split_expression("union //a") {
+      Err(SelectorError::MissingOperand { offset }) => assert_eq!(offset, 0),
+      _ => panic!("expected a missing operand"),
+    }
+    match split_expression("//é except ") {
+      Err(SelectorError::MissingOperand { offset }) => assert_eq!(offset, 10),
+      _ => panic!("expected a missing operand"),
+    }
+  }
+
+  fn path(localname: Option<&str>) -> Box<SelectorExpression> {
+    let predicate = match localname {
+      Some(localname) => named(localname),
+      None => checked(any(), "element", vec![]),
+    };
+    Box::new(SelectorExpression::Path(compile(vec![(
+      Axis::Descendant,
+      predicate,
+    )])))
+  }
+
+  fn combine(
+    xml: &str,
+    operation: SetOperation,
+    left: Option<&str>,
+    right: Option<&str>,
+  ) -> Vec<String> {
+    let document = Document::parse(xml).unwrap();
+    let expression = SelectorExpression::Combined(operation, path(left), path(right));
+    describe(resolve_expression(document.root(), &expression, None))
+  }
+
+  const MIXED: &str = "<r><a>1</a><b/><a>2</a><c/></r>";
+
+  #[test]
+  fn set_operations_yield_document_order() {
+    let union = combine(MIXED, SetOperation::Union, Some("c"), Some("a"));
+    assert_eq!(union, ["1", "2", "c"]);
+    let union = combine(MIXED, SetOperation::Union, Some("a"), Some("a"));
+    assert_eq!(union, ["1", "2"]);
+    let intersection = combine(MIXED, SetOperation::Intersection, None, Some("b"));
+    assert_eq!(intersection, ["b"]);
+    let difference = combine(MIXED, SetOperation::Difference, None, Some("a"));
+    assert_eq!(difference, ["r", "b", "c"]);
+    let difference = combine(MIXED, SetOperation::Difference, Some("a"), None);
+    assert_eq!(difference, [] as [&str; 0]);
+  }
+
+  #[test]
+  fn matching_walks_back_to_the_start() {
+    let document = Document::parse(LIST).unwrap();
+    let selector = compile(child_b("last", vec![]));
+    let matched: Vec<&str> = document
+      .descendants()
+      .filter(|node| selector.matches(document.root(), *node))
+      .filter_map(|node| node.text())
+      .collect();
+    assert_eq!(matched, ["3", "4"]);
+    let second = document
+      .descendants()
+      .filter(|node| node.has_tag_name("a"))
+      .nth(1)
+      .unwrap();
+    let three = document
+      .descendants()
+      .find(|node| node.text() == Some("3"))
+      .unwrap();
+    assert!(selector.matches(second, second.first_element_child().unwrap()));
+    assert!(!selector.matches(second, three));
+  }
+
+  #[test]
+  fn matching_repeated_descendant_steps_stays_fast() {
+    let depth = 60;
+    let xml = format!("{}{}", "<a>".repeat(depth), "</a>".repeat(depth));
+    let document = Document::parse(&xml).unwrap();
+    let deepest = document.descendants().last().unwrap();
+    let descendants = |count| {
+      iter::repeat_with(|| (Axis::Descendant, named("a")))
+        .take(count)
+        .collect::<Vec<_>>()
+    };
+    assert!(compile(descendants(depth / 2)).matches(document.root(), deepest));
+    // Every path back has to be ruled out before the missing `b` is found.
+    let mut steps = vec![(Axis::Descendant, named("b"))];
+    steps.extend(descendants(depth / 2));
+    assert!(!compile(steps).matches(document.root(), deepest));
+  }
+
+  fn with_attribute(
+    mut predicate: Predicate,
+    localname: &str,
+    namespace: Option<Namespace>,
+    op: AttributeRequirementOperation,
+  ) -> Predicate {
+    predicate.attributes.push(AttributeRequirement {
+      name: NameRequirement {
+        localname: Some(localname.to_owned()),
+        namespace,
+      },
+      op,
+    });
+    predicate
+  }
+
+  #[test]
+  fn indexed_lookups_find_repeated_and_namespaced_values() {
+    let xml = r#"<r xmlns:x="urn:x"><a id="p">1</a><b x:id="p">2</b><c id="p" class="k">3</c><d x:class="k">4</d></r>"#;
+    let document = Document::parse(xml).unwrap();
+    let index = DocumentIndex::new(&document);
+    let resolve = |predicate| {
+      let selector = compile(vec![(Axis::Descendant, predicate)]);
+      let indexed = describe(resolve_selector(document.root(), &selector, Some(&index)));
+      assert_eq!(
+        indexed,
+        describe(resolve_selector(document.root(), &selector, None))
+      );
+      indexed
+    };
+    let equals = || AttributeRequirementOperation::Equals("p".to_owned());
+    assert_eq!(
+      resolve(with_attribute(any(), "id", None, equals())),
+      ["1", "2", "3"]
+    );
+    let in_x = || Some(Namespace::Uri("urn:x".to_owned()));
+    assert_eq!(
+      resolve(with_attribute(any(), "id", in_x(), equals())),
+      ["2"]
+    );
+    let contains = || AttributeRequirementOperation::Contains("k".to_owned());
+    assert_eq!(
+      resolve(with_attribute(any(), "class", None, contains())),
+      ["3", "4"]
+    );
+    assert_eq!(
+      resolve(with_attribute(any(), "class", in_x(), contains())),
+      ["4"]
+    );
+  }
+
+  fn compile_error(steps: Vec<(Axis, Predicate)>) -> SelectorError {
+    let steps = steps
+      .into_iter()
+      .map(|(axis, predicate)| Step { axis, predicate })
+      .collect();
+    match ActionableSelector::from_selector(Selector { steps }) {
+      Ok(_) => panic!("the selector compiled"),
+      Err(err) => err,
+    }
+  }
+
+  #[test]
+  fn unknown_check_functions_are_rejected_when_compiled() {
+    match compile_error(child_b("foo", vec![])) {
+      SelectorError::CheckError {
+        step: 1,
+        function,
+        reason: CheckErrorReason::UnknownFunction,
+      } => assert_eq!(function, "foo"),
+      err => panic!("unexpected error {:?}", err),
+    }
+  }
+
+  #[test]
+  fn bad_check_arguments_are_rejected_when_compiled() {
+    match compile_error(child_b("nth", vec![number("x")])) {
+      SelectorError::CheckError {
+        step: 1,
+        function,
+        reason: CheckErrorReason::BadArgument { index: 0, .. },
+      } => assert_eq!(function, "nth"),
+      err => panic!("unexpected error {:?}", err),
+    }
+    let missing_value = vec![(
+      Axis::Descendant,
+      checked(any(), "attr-equals", vec![number("a")]),
+    )];
+    match compile_error(missing_value) {
+      SelectorError::CheckError {
+        step: 0,
+        function,
+        reason:
+          CheckErrorReason::ArgumentCount {
+            min: 2,
+            max: 3,
+            found: 1,
+          },
+      } => assert_eq!(function, "attr-equals"),
+      err => panic!("unexpected error {:?}", err),
+    }
+  }
+
+  const LINKS: &str = r#"<r xmlns:x="urn:x"><a href="Http://Example.com/a.PDF">1</a><a href=" http://example.com/a  b ">2</a><a x:href="http://example.com/b">3</a></r>"#;
+
+  fn links_with(function: &str, args: &[&str]) -> Vec<String> {
+    let args = args.iter().map(|arg| number(arg)).collect();
+    select(
+      LINKS,
+      vec![(Axis::Descendant, checked(any(), function, args))],
+    )
+  }
+
+  #[test]
+  fn attribute_checks_compare_values() {
+    assert_eq!(links_with("attr-starts", &["href", "http:"]), ["3"]);
+    assert_eq!(links_with("attr-ends", &["href", ".PDF"]), ["1"]);
+    assert_eq!(
+      links_with("attr-matches", &["href", r"^http://example\.com/\w$"]),
+      ["3"]
+    );
+  }
+
+  #[test]
+  fn attribute_check_flags_ignore_case_and_collapse_whitespace() {
+    assert_eq!(
+      links_with("attr-starts", &["href", "http:", "i"]),
+      ["1", "3"]
+    );
+    assert_eq!(
+      links_with("attr-starts", &["href", "http:", "n"]),
+      ["2", "3"]
+    );
+    assert_eq!(
+      links_with("attr-equals", &["href", "http://example.com/a b", "n"]),
+      ["2"]
+    );
+    assert_eq!(
+      links_with("attr-matches", &["href", r"example\.com/a\.pdf$", "i"]),
+      ["1"]
+    );
+    assert_eq!(
+      links_with("attr-contains", &["href", "EXAMPLE", "in"]),
+      ["1", "2", "3"]
+    );
+  }
+
+  #[test]
+  fn attribute_checks_can_name_a_namespace() {
+    assert_eq!(links_with("attr-contains", &["href", "b"]), ["2", "3"]);
+    assert_eq!(links_with("attr-contains", &["{urn:x}href", "b"]), ["3"]);
+    assert_eq!(links_with("attr-contains", &["{}href", "b"]), ["2"]);
+  }
+
+  #[test]
+  fn invalid_regular_expressions_are_rejected_when_compiled() {
+    let invalid = vec![(
+      Axis::Descendant,
+      checked(any(), "attr-matches", vec![number("href"), number("(")]),
+    )];
+    match compile_error(invalid) {
+      SelectorError::CheckError {
+        step: 0,
+        function,
+        reason:
+          CheckErrorReason::BadArgument {
+            index: 1,
+            expected: "a valid regular expression",
+          },
+      } => assert_eq!(function, "attr-matches"),
+      err => panic!("unexpected error {:?}", err),
+    }
+  }
+
+  const NOTES: &str = "<r><p>a<b>c</b></p><p>\n  Note:  see\n  <b>c</b></p></r>";
+
+  fn elements_with(function: &str, args: &[&str]) -> Vec<String> {
+    let args = args.iter().map(|arg| number(arg)).collect();
+    let predicate = checked(checked(any(), "element", vec![]), function, args);
+    select(NOTES, vec![(Axis::Descendant, predicate)])
+  }
+
+  #[test]
+  fn text_checks_see_nested_text_and_own_text_checks_do_not() {
+    assert_eq!(elements_with("text-equals", &["ac"]), ["a"]);
+    assert!(elements_with("own-text-equals", &["ac"]).is_empty());
+    assert_eq!(elements_with("own-text-equals", &["a"]), ["a"]);
+    assert_eq!(elements_with("own-text-contains", &["c"]), ["c", "c"]);
+  }
+
+  #[test]
+  fn text_checks_can_collapse_whitespace() {
+    assert!(elements_with("own-text-starts", &["Note: see"]).is_empty());
+    assert_eq!(
+      elements_with("own-text-equals", &["Note: see", "n"]),
+      ["\n  Note:  see\n  "]
+    );
+    assert_eq!(
+      elements_with("text-equals", &["note: see c", "in"]),
+      ["\n  Note:  see\n  "]
+    );
+  }
+
+  #[test]
+  fn only_typed_steps_select_comments_and_pis() {
+    let document = Document::parse("<r><!--c--><a/><?p v?><?q w?></r>").unwrap();
+    let r = document.root_element();
+    let children = |predicate| {
+      let expression = SelectorExpression::Path(compile(vec![(Axis::Child, predicate)]));
+      let selected: Vec<usize> = resolve_expression(r, &expression, None)
+        .map(|node| node.id().get_usize())
+        .collect();
+      for child in r.children() {
+        let id = child.id().get_usize();
+        assert_eq!(expression.matches(r, child), selected.contains(&id));
+      }
+      selected
+    };
+    assert_eq!(children(any()), [3]);
+    assert_eq!(children(checked(any(), "comment", vec![])), [2]);
+    assert_eq!(children(checked(any(), "pi", vec![])), [4, 5]);
+    assert_eq!(children(checked(any(), "pi", vec![number("q")])), [5]);
+  }
 }
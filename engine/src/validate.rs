@@ -0,0 +1,156 @@
+use crate::error::{InstructionError, InvalidResult};
+use crate::index::DocumentIndex;
+use crate::schema::{QualifiedName, TransformResult, WriteInstruction};
+
+/// Checks that a transform result can be written out wherever it ends up: its elements are
+/// balanced, attributes and namespaces only follow a start tag, and every node it names exists.
+/// Done as results arrive, so that the error can name the transform responsible.
+pub(crate) fn validate_result(
+  result: &TransformResult,
+  index: &DocumentIndex,
+) -> Result<(), Box<InvalidResult>> {
+  let invalid = |instruction: Option<usize>, problem: InstructionError| {
+    Box::new(InvalidResult {
+      node_id: result.node_id,
+      mode: result.mode.clone(),
+      selector: result.selector.clone(),
+      instruction,
+      problem,
+    })
+  };
+  if !index.contains(result.node_id) {
+    return Err(invalid(None, InstructionError::UnknownNode(result.node_id)));
+  }
+  let mut open: Vec<&QualifiedName> = vec![];
+  // Attributes and namespaces may only follow a start tag or each other, so a result cannot open
+  // with them either. They would fail to serialize, unless the result happened to be written
+  // right after its parent's start tag, where they would quietly join the parent's attributes.
+  let mut in_start_tag = false;
+  for (position, instruction) in result.instructions.iter().enumerate() {
+    let at = |problem| Err(invalid(Some(position), problem));
+    match instruction {
+      WriteInstruction::StartElement { qualified_name } => open.push(qualified_name),
+      WriteInstruction::EndElement { qualified_name } => match open.pop() {
+        None => return at(InstructionError::UnopenedEnd(qualified_name.clone())),
+        Some(start)
+          if start.uri != qualified_name.uri || start.local_name != qualified_name.local_name =>
+        {
+          return at(InstructionError::MismatchedEnd {
+            start: start.clone(),
+            end: qualified_name.clone(),
+          })
+        }
+        Some(_) => {}
+      },
+      WriteInstruction::Attributes { .. } if !in_start_tag => {
+        return at(InstructionError::MisplacedAttributes)
+      }
+      WriteInstruction::Namespaces { .. } if !in_start_tag => {
+        return at(InstructionError::MisplacedNamespaces)
+      }
+      WriteInstruction::Replace { node_id, .. } if !index.contains(*node_id) => {
+        return at(InstructionError::UnknownNode(*node_id))
+      }
+      WriteInstruction::Document if result.node_id != 0 => return at(InstructionError::Document),
+      _ => {}
+    }
+    in_start_tag = matches!(
+      instruction,
+      WriteInstruction::StartElement { .. }
+        | WriteInstruction::Attributes { .. }
+        | WriteInstruction::Namespaces { .. }
+    );
+  }
+  match open.pop() {
+    Some(start) => Err(invalid(None, InstructionError::Unclosed(start.clone()))),
+    None => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use roxmltree::Document;
+  use serde_json::json;
+
+  fn validate(node_id: usize, instructions: serde_json::Value) -> Result<(), Box<InvalidResult>> {
+    let document = Document::parse("<r><a/></r>").unwrap();
+    let result = TransformResult {
+      node_id,
+      mode: "default".to_owned(),
+      selector: "//a".to_owned(),
+      instructions: serde_json::from_value(instructions).unwrap(),
+      index: 0,
+      priority: 0,
+    };
+    validate_result(&result, &DocumentIndex::new(&document))
+  }
+
+  fn problem(node_id: usize, instructions: serde_json::Value) -> (Option<usize>, InstructionError) {
+    let invalid = validate(node_id, instructions).unwrap_err();
+    (invalid.instruction, invalid.problem)
+  }
+
+  #[test]
+  fn only_the_root_may_be_written_as_a_document() {
+    let instructions = json!([{ "D": null }, { "R": { "n": 1, "m": "default" } }]);
+    assert!(validate(0, instructions.clone()).is_ok());
+    assert!(matches!(
+      problem(2, instructions),
+      (Some(0), InstructionError::Document)
+    ));
+  }
+
+  #[test]
+  fn attributes_and_namespaces_need_a_start_tag_in_the_same_result() {
+    let attributes = json!({ "A": { "a": [] } });
+    let namespaces = json!({ "N": { "n": [] } });
+    let start = json!({ "S": { "q": { "u": "", "l": "b" } } });
+    let end = json!({ "E": { "q": { "u": "", "l": "b" } } });
+    let text = json!({ "T": { "t": "x" } });
+    let valid = json!([start, namespaces, attributes, text, end]);
+    assert!(validate(2, valid).is_ok());
+    assert!(matches!(
+      problem(2, json!([attributes, text])),
+      (Some(0), InstructionError::MisplacedAttributes)
+    ));
+    assert!(matches!(
+      problem(2, json!([namespaces])),
+      (Some(0), InstructionError::MisplacedNamespaces)
+    ));
+    assert!(matches!(
+      problem(2, json!([start, text, attributes, end])),
+      (Some(2), InstructionError::MisplacedAttributes)
+    ));
+  }
+
+  #[test]
+  fn elements_must_balance() {
+    let start = |name: &str| json!({ "S": { "q": { "u": "", "l": name } } });
+    let end = |name: &str| json!({ "E": { "q": { "u": "", "l": name } } });
+    assert!(matches!(
+      problem(2, json!([start("b"), end("c")])),
+      (Some(1), InstructionError::MismatchedEnd { .. })
+    ));
+    assert!(matches!(
+      problem(2, json!([end("b")])),
+      (Some(0), InstructionError::UnopenedEnd(_))
+    ));
+    assert!(matches!(
+      problem(2, json!([start("b"), start("c"), end("c")])),
+      (None, InstructionError::Unclosed(_))
+    ));
+  }
+
+  #[test]
+  fn replaced_and_replacing_nodes_must_exist() {
+    assert!(matches!(
+      problem(9, json!([])),
+      (None, InstructionError::UnknownNode(9))
+    ));
+    assert!(matches!(
+      problem(2, json!([{ "R": { "n": 9, "m": "default" } }])),
+      (Some(0), InstructionError::UnknownNode(9))
+    ));
+  }
+}
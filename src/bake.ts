@@ -1,9 +1,20 @@
 import fs from 'fs'
 import path from 'path'
 import yaml from 'js-yaml'
-import { UnixSocketBroker } from './node'
+import { RACE_POLICIES, RacePolicy, UnixSocketBroker } from './node'
 import workerFarm from 'worker-farm'
 
+const racePolicy = (races: unknown): RacePolicy | undefined => {
+  if (races == null) {
+    return undefined
+  }
+  const policy = RACE_POLICIES.find(policy => policy === races)
+  if (policy == null) {
+    throw new Error(`Unknown races policy ${JSON.stringify(races)} in the manifest, expected one of ${RACE_POLICIES.join(', ')}`)
+  }
+  return policy
+}
+
 // https://stackoverflow.com/a/37980601/1502122
 const range = (n: number): Array<number> => [...Array(n).keys()]
 
@@ -52,6 +63,7 @@ const runCommand = async(socketPath: string, manifestPath: string, numWorkers: n
       })
     }
 
+    await broker.configure({ races: racePolicy(manifest.races) })
     await spawnWorkers(transformsPath, fixturesPath)
 
     await broker.reportComplete()
@@ -62,6 +74,7 @@ const runCommand = async(socketPath: string, manifestPath: string, numWorkers: n
     })
   } finally {
     workerFarm.end(workers)
+    await broker.close()
   }
 }
 
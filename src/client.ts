@@ -1,6 +1,7 @@
 import async from 'async'
-import { WriteInstruction, StartElement, Attributes, Text, EndElement, Replace as ReplaceInstruction } from './write-instruction'
-import { Node, QualifiedName, Attribute } from './node'
+import { WriteInstruction, StartElement, Attributes, Text, EndElement, PI, Comment, Replace as ReplaceInstruction } from './write-instruction'
+import { Node, QualifiedName, Attribute, ReplicatorError } from './node'
+export { ReplicatorError } from './node'
 
 type ComponentResult = Array<WriteInstruction>
 type JsxAttributes = any
@@ -16,18 +17,35 @@ export class Transform {
   selector: string
   mode: string
   replace: ReplacementFunction
+  // breaks races under the engine's priority policy; higher wins
+  priority: number
+  // position in the manifest's list of transforms, set when the transforms are loaded
+  index: number
 
-  constructor(selector: string, mode: string, replace: ReplacementFunction) {
+  constructor(selector: string, mode: string, replace: ReplacementFunction, priority = 0) {
     this.selector = selector
     this.mode = mode
     this.replace = replace
+    this.priority = priority
+    this.index = 0
   }
 
   async resolve(root: Node, fixtures: any): Promise<Array<TransformResult>> {
     const replaced = await root.select(this.selector)
     // eslint-disable-next-line @typescript-eslint/no-misused-promises
     return async.map(replaced, async node => {
-      return new TransformResult(node.nodeID, this.mode, this.selector, await this.replace(node, fixtures))
+      try {
+        const instructions = await this.replace(node, fixtures)
+        return new TransformResult(node.nodeID, this.mode, this.selector, instructions, this.index, this.priority)
+      } catch (err) {
+        // only objects can carry the node, so anything else thrown is wrapped
+        const error = typeof err === 'object' && err !== null && Object.isExtensible(err)
+          ? err
+          : new ReplicatorError(undefined, `Transform threw ${String(err)}`, { nodeID: node.nodeID })
+        // lets the engine report where in the source the failing node is
+        error.nodeID = error.nodeID ?? node.nodeID
+        throw error
+      }
     })
   }
 }
@@ -37,12 +55,16 @@ export class TransformResult {
   mode: string
   selector: string
   instructions: Array<WriteInstruction>
+  index: number
+  priority: number
 
-  constructor(nodeID: number, mode: string, selector: string, instructions?: Array<WriteInstruction>) {
+  constructor(nodeID: number, mode: string, selector: string, instructions?: Array<WriteInstruction>, index = 0, priority = 0) {
     this.nodeID = nodeID
     this.mode = mode
     this.selector = selector
     this.instructions = instructions ?? []
+    this.index = index
+    this.priority = priority
   }
 }
 
@@ -53,9 +75,15 @@ export const Copy: ComponentFunction = async({ attributes, children }) => {
     throw new Error('Must provide item to Copy.')
   }
   let nodeName = item.name()
-  if (nodeName.localName === '#text') {
+  if (item.isText()) {
     return [new Text(await item.text())]
   }
+  if (item.isComment()) {
+    return [new Comment(await item.text())]
+  }
+  if (item.isPI()) {
+    return [new PI(item.piTarget() as string, await item.text())]
+  }
   if (attributes.nameMap != null) {
     nodeName = QualifiedName.fromExpandedName(attributes.nameMap(nodeName.localName, nodeName.uri))
   }
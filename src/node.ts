@@ -2,6 +2,7 @@ import { Socket } from 'net'
 import path from 'path'
 import { TransformResult } from './client'
 import { Sema } from 'async-sema'
+import { encode, decode } from '@msgpack/msgpack'
 
 export class QualifiedName {
   localName: string
@@ -45,70 +46,317 @@ export class Attribute {
   }
 }
 
-const selectionRequest = (nodeID: number, selector: string): string => {
-  return JSON.stringify({
-    S: { n: nodeID, s: selector }
-  })
+export type ErrorKind =
+  | 'selector-parse'
+  | 'unsupported-check'
+  | 'bad-check-argument'
+  | 'unknown-node'
+  | 'serialization'
+  | 'not-batchable'
+  | 'malformed-request'
+  | 'payload-too-large'
+  | 'protocol-version'
+  | 'invalid-result'
+
+export interface ErrorDetails {
+  // character offset into the selector
+  offset?: number
+  // character offset of the selector path that failed to parse, when the failure's own
+  // position is not known
+  pathOffset?: number
+  // index of the selector step, counted from 0
+  step?: number
+  function?: string
+  nodeID?: number
+  // index of the offending write instruction
+  instruction?: number
+  // largest payload the engine accepts, in bytes
+  limit?: number
+  // protocol version the engine speaks
+  version?: number
+}
+
+// A request the engine refused. `kind` says why, so callers don't have to parse the message. It is
+// left out when a transform threw something other than an Error, which is wrapped in one of these.
+export class ReplicatorError extends Error {
+  kind?: ErrorKind
+  details: ErrorDetails
+
+  constructor(kind: ErrorKind | undefined, message: string, details: ErrorDetails) {
+    super(message)
+    this.name = 'ReplicatorError'
+    this.kind = kind
+    this.details = details
+  }
+
+  static fromResponse(badRequest: any): ReplicatorError {
+    const details = badRequest.d ?? {}
+    return new ReplicatorError(badRequest.k, badRequest.r, {
+      offset: details.o,
+      pathOffset: details.p,
+      step: details.s,
+      function: details.f,
+      nodeID: details.n,
+      instruction: details.i,
+      limit: details.l,
+      version: details.v
+    })
+  }
+}
+
+export type DescriptorField =
+  | 'name'
+  | 'kind'
+  | 'attributes'
+  | 'namespaces'
+  | 'parent'
+  | 'children'
+  | 'ownText'
+  | 'text'
+  | 'depth'
+  | 'siblingIndex'
+const descriptorFieldKeys: { [field in DescriptorField]: string } = {
+  name: 'q',
+  kind: 'k',
+  attributes: 'a',
+  namespaces: 's',
+  parent: 'p',
+  children: 'c',
+  ownText: 'o',
+  text: 't',
+  depth: 'd',
+  siblingIndex: 'i'
+}
+
+export interface Location {
+  line: number
+  column: number
+}
+
+export interface NodeDescriptor {
+  nodeID: number
+  qName?: QualifiedName
+  kind?: 'root' | 'element' | 'text' | 'comment' | 'pi'
+  attributes?: Array<Attribute>
+  namespaces?: Array<{ prefix: string, uri: string }>
+  parent?: number | null
+  children?: Array<number>
+  ownText?: string
+  text?: string
+  // 0 for the root, 1 for the document element
+  depth?: number
+  // the number of element siblings before the node
+  siblingIndex?: number
+}
+
+const nodeKinds: { [key: string]: NodeDescriptor['kind'] } = {
+  r: 'root',
+  e: 'element',
+  t: 'text',
+  c: 'comment',
+  p: 'pi'
+}
+
+const parseDescriptor = (descriptor: any): NodeDescriptor => {
+  return {
+    nodeID: descriptor.n,
+    qName: descriptor.q == null ? undefined : new QualifiedName(descriptor.q.l, descriptor.q.u),
+    kind: descriptor.k == null ? undefined : nodeKinds[descriptor.k],
+    attributes: descriptor.a?.map((attribute: any) => {
+      return new Attribute(new QualifiedName(attribute.q.l, attribute.q.u), attribute.v)
+    }),
+    namespaces: descriptor.s?.map((namespace: any) => ({ prefix: namespace.p, uri: namespace.u })),
+    parent: descriptor.p,
+    children: descriptor.c,
+    ownText: descriptor.o,
+    text: descriptor.t,
+    depth: descriptor.d,
+    siblingIndex: descriptor.i
+  }
 }
-const textRequest = (nodeID: number): string => {
-  return JSON.stringify({
+
+// Bump whenever the shape of requests or responses changes. The engine refuses workers that
+// speak a different version.
+export const PROTOCOL_VERSION = 1
+const CAPABILITIES = ['batch', 'describe', 'serialize', 'location', 'matches', 'relations', 'configure']
+// encodings this worker can speak besides JSON, which the Hello itself is always sent in
+const ENCODINGS = ['msgpack']
+
+type Encoding = 'json' | 'msgpack'
+
+const helloRequest = (): any => {
+  return {
+    HI: { v: PROTOCOL_VERSION, c: CAPABILITIES, e: ENCODINGS }
+  }
+}
+const selectionRequest = (nodeID: number, selector: string, limit?: number, fields?: Array<DescriptorField>): any => {
+  return {
+    S: { n: nodeID, s: selector, l: limit, f: fields?.map(field => descriptorFieldKeys[field]) }
+  }
+}
+const locationRequest = (nodeID: number): any => {
+  return {
+    L: { n: nodeID }
+  }
+}
+const describeRequest = (nodeID: number, fields?: Array<DescriptorField>): any => {
+  return {
+    D: { n: nodeID, f: fields?.map(field => descriptorFieldKeys[field]) }
+  }
+}
+const matchesRequest = (nodeIDs: Array<number>, selector: string): any => {
+  return {
+    M: { n: nodeIDs, s: selector }
+  }
+}
+const textRequest = (nodeID: number): any => {
+  return {
     T: { n: nodeID }
-  })
+  }
 }
-const attributeRequest = (nodeID: number): string => {
-  return JSON.stringify({
+const attributeRequest = (nodeID: number): any => {
+  return {
     A: { n: nodeID }
-  })
+  }
+}
+const serializeRequest = (nodeID: number, inner: boolean): any => {
+  return {
+    X: { n: nodeID, i: inner }
+  }
 }
-const reportResultRequest = (results: Array<TransformResult>): string => {
-  return JSON.stringify({
+const orderRequest = (first: number, second: number): any => {
+  return {
+    O: { a: first, b: second }
+  }
+}
+const ancestorRequest = (ancestorID: number, nodeID: number): any => {
+  return {
+    AN: { a: ancestorID, n: nodeID }
+  }
+}
+const closestRequest = (nodeID: number, selector: string): any => {
+  return {
+    CL: { n: nodeID, s: selector }
+  }
+}
+const batchRequest = (requests: Array<any>): any => {
+  return {
+    Q: { r: requests }
+  }
+}
+const reportResultRequest = (results: Array<TransformResult>): any => {
+  return {
     R: {
       r: results.map(transformResult => {
         return {
           n: transformResult.nodeID,
           m: transformResult.mode,
           s: transformResult.selector,
-          i: transformResult.instructions.map(instruction => instruction.toRequestObj())
+          i: transformResult.instructions.map(instruction => instruction.toRequestObj()),
+          x: transformResult.index,
+          p: transformResult.priority
         }
       })
     }
-  })
+  }
 }
-const reportCountRequest = (count: number): string => {
-  return JSON.stringify({
+const reportCountRequest = (count: number): any => {
+  return {
     C: { c: count }
-  })
+  }
 }
-const reportComplete = (): string => {
-  return JSON.stringify({
+const configureRequest = (config: EngineConfig): any => {
+  return {
+    CF: { r: config.races }
+  }
+}
+const reportComplete = (): any => {
+  return {
     CC: null
-  })
+  }
+}
+const reportErrorRequest = (error: Error): any => {
+  return {
+    E: {
+      m: error.stack ?? error.toString(),
+      n: (error as any).nodeID,
+      k: error instanceof ReplicatorError ? error.kind : undefined
+    }
+  }
 }
-const reportErrorRequest = (error: Error): string => {
-  return JSON.stringify({
-    E: { m: error.stack ?? error.toString() }
+
+// The outcome of one request in a batch. Each request is answered on its own, so one failing
+// leaves the others' responses intact.
+export type BatchResult =
+  | { ok: true, value: any }
+  | { ok: false, error: ReplicatorError }
+
+// The responses to every request in a batch, for callers that cannot use some without the rest.
+const batchValues = (results: Array<BatchResult>): Array<any> => {
+  return results.map(result => {
+    if (!result.ok) {
+      throw result.error
+    }
+    return result.value
   })
 }
 
+interface PendingRequest {
+  resolve: (response: any) => void
+  reject: (err: Error) => void
+}
+
 export class UnixSocketBroker implements Broker {
   socketFile: string
   count: number
   socketLock: Sema
+  nextRequestID: number
+  pending: Map<number, PendingRequest>
+  // chunks of frames not yet complete, only joined once a whole frame has arrived
+  received: Array<Buffer>
+  receivedLength: number
+  connection?: Promise<Socket>
+  // what both this worker and the engine support, known once connected
+  capabilities: Array<string>
+  // what frames are encoded in, decided by the engine when connecting
+  encoding: Encoding
 
   constructor(socketFile: string) {
     this.socketFile = path.resolve(socketFile)
     this.count = 0
     this.socketLock = new Sema(require('os').cpus().length * 2)
+    this.nextRequestID = 0
+    this.pending = new Map()
+    this.received = []
+    this.receivedLength = 0
+    this.capabilities = []
+    this.encoding = 'json'
   }
 
-  async select(nodeID: number, selector: string): Promise<Array<Node>> {
-    const response = await this.socketConnection(selectionRequest(nodeID, selector))
+  async select(nodeID: number, selector: string, limit?: number, fields?: Array<DescriptorField>): Promise<Array<Node>> {
+    const response = await this.socketConnection(selectionRequest(nodeID, selector, limit, fields))
     return response.S.e.map((element: any) => {
       const qName = element.q
-      return new Node(element.n, new QualifiedName(qName.l, qName.u), this)
+      const descriptor = element.d == null ? undefined : parseDescriptor(element.d)
+      return new Node(element.n, new QualifiedName(qName.l, qName.u), this, descriptor)
     })
   }
 
+  async getLocation(nodeID: number): Promise<Location> {
+    const response = await this.socketConnection(locationRequest(nodeID))
+    return { line: response.L.o.l, column: response.L.o.c }
+  }
+
+  async describe(nodeID: number, fields?: Array<DescriptorField>): Promise<NodeDescriptor> {
+    const response = await this.socketConnection(describeRequest(nodeID, fields))
+    return parseDescriptor(response.D.d)
+  }
+
+  async matches(nodeIDs: Array<number>, selector: string): Promise<Array<boolean>> {
+    const response = await this.socketConnection(matchesRequest(nodeIDs, selector))
+    return response.M.m
+  }
+
   async getText(nodeID: number): Promise<string> {
     const response = await this.socketConnection(textRequest(nodeID))
     return response.T.t
@@ -122,6 +370,43 @@ export class UnixSocketBroker implements Broker {
     })
   }
 
+  async serialize(nodeID: number, inner: boolean): Promise<string> {
+    const response = await this.socketConnection(serializeRequest(nodeID, inner))
+    return response.X.x
+  }
+
+  async compareOrder(first: number, second: number): Promise<number> {
+    const response = await this.socketConnection(orderRequest(first, second))
+    return response.O.o
+  }
+
+  async isAncestor(ancestorID: number, nodeID: number): Promise<boolean> {
+    const response = await this.socketConnection(ancestorRequest(ancestorID, nodeID))
+    return response.AN.r
+  }
+
+  async closest(nodeID: number, selector: string): Promise<Node | undefined> {
+    const response = await this.socketConnection(closestRequest(nodeID, selector))
+    const element = response.CL.e
+    if (element == null) {
+      return undefined
+    }
+    return new Node(element.n, new QualifiedName(element.q.l, element.q.u), this)
+  }
+
+  async selectEach(nodeIDs: Array<number>, selector: string): Promise<Array<Array<Node>>> {
+    const results = await this.batch(nodeIDs.map(nodeID => selectionRequest(nodeID, selector)))
+    return batchValues(results).map(response => response.S.e.map((element: any) => {
+      const qName = element.q
+      return new Node(element.n, new QualifiedName(qName.l, qName.u), this)
+    }))
+  }
+
+  async getTexts(nodeIDs: Array<number>): Promise<Array<string>> {
+    const results = await this.batch(nodeIDs.map(textRequest))
+    return batchValues(results).map(response => response.T.t)
+  }
+
   async getRoot(): Promise<Node> {
     return Promise.resolve(new Node(0, new QualifiedName('ROOT', ''), this))
   }
@@ -138,76 +423,197 @@ export class UnixSocketBroker implements Broker {
     return this.socketConnectionOneWay(reportComplete())
   }
 
+  async configure(config: EngineConfig): Promise<void> {
+    return this.socketConnectionOneWay(configureRequest(config))
+  }
+
   async reportError(error: Error): Promise<void> {
     return this.socketConnectionOneWay(reportErrorRequest(error))
   }
 
-  async socketConnectionOneWay(payload: string): Promise<void> {
-    await this.socketLock.acquire()
-    return new Promise((resolve, reject) => {
-      const connection: Socket = new Socket()
-        .on('connect', () => {
-          connection.end(payload)
-        })
-        .on('end', () => {
-          this.socketLock.release()
-          resolve(undefined)
-        })
-        .on('error', (err: any) => {
-          if (err.code === 'EAGAIN') {
-            connection.connect(this.socketFile)
-            return
-          }
-          connection.destroy()
-          this.socketLock.release()
-          reject(err)
-        })
-        .connect(this.socketFile)
+  // Sends the requests as one batch. The engine answers each request separately, so failures
+  // are returned next to the other responses and callers decide what they mean for the rest.
+  async batch(requests: Array<any>): Promise<Array<BatchResult>> {
+    const response = await this.socketConnection(batchRequest(requests))
+    return response.Q.r.map((subResponse: any): BatchResult => {
+      if (subResponse?.B != null) {
+        return { ok: false, error: ReplicatorError.fromResponse(subResponse.B) }
+      }
+      return { ok: true, value: subResponse }
     })
   }
 
-  async socketConnection(payload: string): Promise<any> {
+  async close(): Promise<void> {
+    if (this.connection == null) {
+      return
+    }
+    const connection = await this.connection
+    return new Promise(resolve => connection.end(resolve))
+  }
+
+  async socketConnectionOneWay(request: any): Promise<void> {
+    await this.socketConnection(request)
+  }
+
+  async socketConnection(request: any): Promise<any> {
+    const connection = await this.connect()
     await this.socketLock.acquire()
+    try {
+      return await this.send(connection, request)
+    } finally {
+      this.socketLock.release()
+    }
+  }
+
+  async send(connection: Socket, request: any): Promise<any> {
+    const requestID = this.nextRequestID
+    this.nextRequestID = (this.nextRequestID + 1) >>> 0
     return new Promise((resolve, reject) => {
-      const buffers: Array<Buffer> = []
-      const connection = new Socket()
-        .on('connect', () => {
-          connection.end(payload)
-        })
-        .on('data', data => {
-          buffers.push(data)
-        })
-        .on('end', () => {
-          const responseString = Buffer.concat(buffers).toString()
-          const responseObj = JSON.parse(responseString)
-          this.socketLock.release()
-          if (responseObj?.B?.r != null) {
-            reject(new Error(responseObj.B.r))
-          }
-          resolve(responseObj)
-        })
-        .on('error', (err: any) => {
-          if (err.code === 'EAGAIN') {
-            connection.connect(this.socketFile)
-            return
-          }
-          connection.destroy()
-          this.socketLock.release()
-          reject(err)
-        })
-        .connect(this.socketFile)
+      this.pending.set(requestID, { resolve, reject })
+      // frames are the payload length and the request id as big-endian 32 bit integers,
+      // followed by the payload in the connection's encoding
+      const payload = this.encode(request)
+      const header = Buffer.alloc(8)
+      header.writeUInt32BE(payload.length, 0)
+      header.writeUInt32BE(requestID, 4)
+      connection.write(Buffer.concat([header, payload]))
     })
   }
+
+  // The engine only answers a connection once it has been greeted with a protocol version it
+  // speaks, so every connection starts with a Hello.
+  async connect(): Promise<Socket> {
+    if (this.connection == null) {
+      this.connection = new Promise((resolve, reject) => {
+        const connection: Socket = new Socket()
+          .on('connect', () => {
+            this.send(connection, helloRequest())
+              .then(response => {
+                this.capabilities = response.HI.c
+                this.encoding = response.HI.e ?? 'json'
+                resolve(connection)
+              })
+              .catch(reject)
+          })
+          .on('data', data => {
+            this.receive(data)
+          })
+          .on('close', () => {
+            this.connection = undefined
+            this.received = []
+            this.receivedLength = 0
+            this.encoding = 'json'
+            this.rejectPending(new Error('Connection to engine closed'))
+          })
+          .on('error', (err: any) => {
+            if (err.code === 'EAGAIN') {
+              connection.connect(this.socketFile)
+              return
+            }
+            connection.destroy()
+            reject(err)
+            this.rejectPending(err)
+          })
+          .connect(this.socketFile)
+      })
+    }
+    return this.connection
+  }
+
+  receive(data: Buffer): void {
+    this.received.push(data)
+    this.receivedLength += data.length
+    while (this.receivedLength >= 8) {
+      const header = this.takeReceived(8, false)
+      const length = header.readUInt32BE(0)
+      const requestID = header.readUInt32BE(4)
+      if (this.receivedLength < length + 8) {
+        return
+      }
+      const response = this.decode(this.takeReceived(length + 8, true).subarray(8))
+      const pending = this.pending.get(requestID)
+      if (pending == null) {
+        continue
+      }
+      this.pending.delete(requestID)
+      if (response?.B != null) {
+        pending.reject(ReplicatorError.fromResponse(response.B))
+      } else {
+        pending.resolve(response)
+      }
+    }
+  }
+
+  // The first `size` received bytes, removed from what was received when `consume` is set.
+  // Chunks are only joined when the first one is too short.
+  takeReceived(size: number, consume: boolean): Buffer {
+    if (this.received[0].length < size) {
+      this.received = [Buffer.concat(this.received, this.receivedLength)]
+    }
+    const first = this.received[0]
+    if (consume) {
+      this.receivedLength -= size
+      if (first.length === size) {
+        this.received.shift()
+      } else {
+        this.received[0] = first.subarray(size)
+      }
+    }
+    return first.subarray(0, size)
+  }
+
+  encode(request: any): Buffer {
+    if (this.encoding === 'msgpack') {
+      // leave out undefined fields like JSON.stringify does
+      const encoded = encode(request, { ignoreUndefined: true })
+      return Buffer.from(encoded.buffer, encoded.byteOffset, encoded.byteLength)
+    }
+    return Buffer.from(JSON.stringify(request))
+  }
+
+  decode(payload: Buffer): any {
+    if (this.encoding === 'msgpack') {
+      return decode(payload)
+    }
+    return JSON.parse(payload.toString())
+  }
+
+  rejectPending(err: Error): void {
+    const pending = Array.from(this.pending.values())
+    this.pending.clear()
+    pending.forEach(request => request.reject(err))
+  }
+}
+
+// What happens when transforms produce output for the same node in the same mode: the bake fails,
+// the transform declared first wins, the one with the highest priority wins, or all outputs are
+// written in declaration order. Unless told otherwise the engine fails.
+export const RACE_POLICIES = ['fail', 'first', 'priority', 'compose'] as const
+export type RacePolicy = typeof RACE_POLICIES[number]
+
+// Settings from the manifest. The engine's command line takes precedence.
+export interface EngineConfig {
+  races?: RacePolicy
 }
 
 export interface Broker {
-  select(nodeID: number, selector: string): Promise<Array<Node>>
+  select(nodeID: number, selector: string, limit?: number, fields?: Array<DescriptorField>): Promise<Array<Node>>
+  describe(nodeID: number, fields?: Array<DescriptorField>): Promise<NodeDescriptor>
+  matches(nodeIDs: Array<number>, selector: string): Promise<Array<boolean>>
+  selectEach(nodeIDs: Array<number>, selector: string): Promise<Array<Array<Node>>>
   getText(nodeID: number): Promise<string>
+  getTexts(nodeIDs: Array<number>): Promise<Array<string>>
   getAttributes(nodeID: number): Promise<Array<Attribute>>
+  getLocation(nodeID: number): Promise<Location>
+  serialize(nodeID: number, inner: boolean): Promise<string>
+  compareOrder(first: number, second: number): Promise<number>
+  isAncestor(ancestorID: number, nodeID: number): Promise<boolean>
+  closest(nodeID: number, selector: string): Promise<Node | undefined>
   getRoot(): Promise<Node>
   reportResults(result: Array<TransformResult>): Promise<void>
   reportCount(count: number): Promise<void>
   reportComplete(): Promise<void>
+  configure(config: EngineConfig): Promise<void>
   reportError(error: Error): Promise<void>
 }
 
@@ -215,31 +621,47 @@ export class Node {
   nodeID: number
   qName: QualifiedName
   broker: Broker
+  descriptor?: NodeDescriptor
 
-  constructor(nodeID: number, qName: QualifiedName, broker: Broker) {
+  constructor(nodeID: number, qName: QualifiedName, broker: Broker, descriptor?: NodeDescriptor) {
     this.nodeID = nodeID
     this.qName = qName
     this.broker = broker
+    this.descriptor = descriptor
   }
 
-  async select(selector: string): Promise<Array<Node>> {
-    return this.broker.select(this.nodeID, selector)
+  // Passing fields fetches those parts of each selected node's descriptor along with the
+  // selection, saving a round trip per node for text() and attributes().
+  async select(selector: string, fields?: Array<DescriptorField>): Promise<Array<Node>> {
+    return this.broker.select(this.nodeID, selector, undefined, fields)
+  }
+
+  async describe(fields?: Array<DescriptorField>): Promise<NodeDescriptor> {
+    return this.broker.describe(this.nodeID, fields)
   }
 
   async selectOne(selector: string): Promise<Node | undefined> {
-    const selection = await this.broker.select(this.nodeID, selector)
+    const selection = await this.broker.select(this.nodeID, selector, 1)
     if (selection.length === 0) {
       return undefined
     }
     return selection[0]
   }
 
+  async matches(selector: string): Promise<boolean> {
+    const [matches] = await this.broker.matches([this.nodeID], selector)
+    return matches
+  }
+
   async text(): Promise<string> {
+    if (this.descriptor?.text != null) {
+      return this.descriptor.text
+    }
     return this.broker.getText(this.nodeID)
   }
 
   async value(name: QualifiedName | string): Promise<string | undefined> {
-    const attributes = await this.broker.getAttributes(this.nodeID)
+    const attributes = await this.attributes()
     const matchAgainst = typeof name === 'string'
       ? QualifiedName.fromExpandedName(name)
       : name
@@ -248,9 +670,60 @@ export class Node {
   }
 
   async attributes(): Promise<Array<Attribute>> {
+    if (this.descriptor?.attributes != null) {
+      // callers are free to modify the attributes they get back
+      return this.descriptor.attributes.map(attribute => new Attribute(attribute.qName, attribute.value))
+    }
     return this.broker.getAttributes(this.nodeID)
   }
 
+  // where this node starts in the source document
+  async location(): Promise<Location> {
+    return this.broker.getLocation(this.nodeID)
+  }
+
+  // the markup of this node as it appears in the source, with the namespace declarations it
+  // needs to stand alone
+  async outerXML(): Promise<string> {
+    return this.broker.serialize(this.nodeID, false)
+  }
+
+  async innerXML(): Promise<string> {
+    return this.broker.serialize(this.nodeID, true)
+  }
+
+  // negative if this node comes before the other in the document, positive if after
+  async compareOrder(other: Node): Promise<number> {
+    return this.broker.compareOrder(this.nodeID, other.nodeID)
+  }
+
+  async isAncestorOf(other: Node): Promise<boolean> {
+    return this.broker.isAncestor(this.nodeID, other.nodeID)
+  }
+
+  async isDescendantOf(other: Node): Promise<boolean> {
+    return this.broker.isAncestor(other.nodeID, this.nodeID)
+  }
+
+  // the nearest ancestor matching the selector, not counting this node
+  async closest(selector: string): Promise<Node | undefined> {
+    return this.broker.closest(this.nodeID, selector)
+  }
+
+  async depth(): Promise<number> {
+    if (this.descriptor?.depth != null) {
+      return this.descriptor.depth
+    }
+    return (await this.broker.describe(this.nodeID, ['depth'])).depth as number
+  }
+
+  async siblingIndex(): Promise<number> {
+    if (this.descriptor?.siblingIndex != null) {
+      return this.descriptor.siblingIndex
+    }
+    return (await this.broker.describe(this.nodeID, ['siblingIndex'])).siblingIndex as number
+  }
+
   async children(): Promise<Array<Node>> {
     return this.select('/*')
   }
@@ -271,6 +744,18 @@ export class Node {
     return this.name().localName === '#text'
   }
 
+  isComment(): boolean {
+    return this.name().localName === '#comment'
+  }
+
+  isPI(): boolean {
+    return this.name().localName.startsWith('?')
+  }
+
+  piTarget(): string | undefined {
+    return this.isPI() ? this.name().localName.slice(1) : undefined
+  }
+
   equals(other: Node): boolean {
     return this.nodeID === other.nodeID
   }
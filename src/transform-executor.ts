@@ -25,6 +25,11 @@ async function helper(args: RunArgs): Promise<void> {
     ? []
     : (await import(args.transformsPath))
       ?.transforms
+      ?.map((transform: Transform, index: number) => {
+        // the engine resolves races by where transforms are declared, not which worker ran them
+        transform.index = index
+        return transform
+      })
       ?.filter((_: any, index: number) => {
         return index % args.numWorkers === args.workerID
       }) as Array<any> | undefined ?? []
@@ -35,6 +40,7 @@ async function helper(args: RunArgs): Promise<void> {
     console.error(`Warning (workerID ${args.workerID}): No transformations were passed to be run`)
   }
   await resolveTransforms(transformsToRun, broker, fixtures)
+  await broker.close()
 }
 
 interface RunArgs {
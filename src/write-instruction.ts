@@ -61,9 +61,31 @@ export class Replace implements WriteInstruction {
   }
 }
 
+export class PI implements WriteInstruction {
+  target: string
+  value: string
+  constructor(target: string, value: string) {
+    this.target = target
+    this.value = value
+  }
+
+  toRequestObj(): any {
+    return { P: { t: this.target, v: this.value } }
+  }
+}
+
+export class Comment implements WriteInstruction {
+  text: string
+  constructor(text: string) {
+    this.text = text
+  }
+
+  toRequestObj(): any {
+    return { C: { t: this.text } }
+  }
+}
+
 // Unimplemented WriteInstructions
 // class StartDocument implements WriteInstruction {}
 // class EndDocument implements WriteInstruction {}
-// class PI implements WriteInstruction {}
-// class Comment implements WriteInstruction {}
 // class Namespaces implements WriteInstruction {}
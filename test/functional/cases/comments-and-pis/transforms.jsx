@@ -0,0 +1,12 @@
+const { Transform, Fragment, queueWriteInstruction } = require('replicator-xml')
+
+module.exports.transforms = [
+  new Transform('//:comment', 'default', async node => {
+    return null
+  }),
+  new Transform('//:pi', 'default', async node => {
+    return (
+      <eoc target={node.piTarget()}>{await node.text()}</eoc>
+    )
+  })
+]
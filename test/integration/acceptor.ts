@@ -2,7 +2,7 @@ import test from 'ava'
 
 import { queueWriteInstruction, Transform, TransformResult } from '../../src/client'
 import { resolveTransforms } from '../../src/transform-executor'
-import { Broker, Node, Attribute, QualifiedName } from '../../src/node'
+import { Broker, EngineConfig, Node, NodeDescriptor, Attribute, Location, QualifiedName } from '../../src/node'
 
 const sleep = async(ms: number): Promise<void> => {
   return new Promise(resolve => setTimeout(resolve, ms))
@@ -27,14 +27,50 @@ class MockBroker implements Broker {
     return Promise.resolve(this.memo.select[nodeID][selector].call(this))
   }
 
+  async matches(nodeIDs: Array<number>, selector: string): Promise<Array<boolean>> {
+    return Promise.resolve(nodeIDs.map(nodeID => this.memo.matches[nodeID][selector].call(this)))
+  }
+
+  async describe(nodeID: number): Promise<NodeDescriptor> {
+    return Promise.resolve(this.memo.describe[nodeID].call(this))
+  }
+
+  async selectEach(nodeIDs: Array<number>, selector: string): Promise<Array<Array<Node>>> {
+    return Promise.all(nodeIDs.map(async nodeID => this.select(nodeID, selector)))
+  }
+
   async getText(nodeID: number): Promise<string> {
     return Promise.resolve(this.memo.getText[nodeID].call(this))
   }
 
+  async getTexts(nodeIDs: Array<number>): Promise<Array<string>> {
+    return Promise.all(nodeIDs.map(async nodeID => this.getText(nodeID)))
+  }
+
   async getAttributes(nodeID: number): Promise<Array<Attribute>> {
     return Promise.resolve(this.memo.getAttributes[nodeID].call(this))
   }
 
+  async getLocation(nodeID: number): Promise<Location> {
+    return Promise.resolve(this.memo.getLocation[nodeID].call(this))
+  }
+
+  async serialize(nodeID: number, inner: boolean): Promise<string> {
+    return Promise.resolve(this.memo.serialize[nodeID][inner ? 'inner' : 'outer'].call(this))
+  }
+
+  async compareOrder(first: number, second: number): Promise<number> {
+    return Promise.resolve(Math.sign(first - second))
+  }
+
+  async isAncestor(ancestorID: number, nodeID: number): Promise<boolean> {
+    return Promise.resolve(this.memo.isAncestor[ancestorID][nodeID].call(this))
+  }
+
+  async closest(nodeID: number, selector: string): Promise<Node | undefined> {
+    return Promise.resolve(this.memo.closest[nodeID][selector].call(this))
+  }
+
   async getRoot(): Promise<Node> {
     return Promise.resolve(this.memo.getRoot.call(this))
   }
@@ -54,6 +90,10 @@ class MockBroker implements Broker {
     return Promise.resolve(undefined)
   }
 
+  async configure(_config: EngineConfig): Promise<void> {
+    return Promise.resolve(undefined)
+  }
+
   async reportError(error: Error): Promise<void> {
     this.error = error
     return Promise.resolve(undefined)